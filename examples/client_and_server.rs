@@ -22,6 +22,6 @@ fn main() {
 	let url = "coap://127.0.0.1:5683/Rust";
 	println!("Client request: {}", url);
 
-	let response: Packet = CoAPClient::request(url).unwrap();
+	let response: Packet = CoAPClient::get(url).unwrap();
 	println!("Server reply: {}", String::from_utf8(response.payload).unwrap());
 }
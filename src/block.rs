@@ -0,0 +1,231 @@
+//! Block-wise transfers ([RFC 7959][spec]).
+//!
+//! [spec]: https://tools.ietf.org/html/rfc7959
+
+use std::collections::{HashMap, LinkedList};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use packet::{Packet, PacketClass, Requests, BlockValue, OptionType};
+
+/// Blocks of 1024 bytes.
+pub const DEFAULT_SIZE_EXPONENT: u8 = 6;
+pub const DEFAULT_CAPACITY: usize = 64;
+/// Largest request body reassembled from Block1 transfers.
+pub const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024;
+
+type BodyKey = (SocketAddr, String);
+
+/// Bodies of block-wise transfers in progress, keyed by the peer and the
+///   request URI.
+pub struct BlockCache {
+    entries: HashMap<BodyKey, (Instant, Packet)>,
+    capacity: usize,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> BlockCache {
+        BlockCache {
+            entries: HashMap::new(),
+            capacity: capacity,
+        }
+    }
+
+    pub fn get(&mut self, address: SocketAddr, key: &str) -> Option<Packet> {
+        self.purge(Instant::now());
+        self.entries.get(&(address, key.to_string())).map(|&(_, ref packet)| packet.clone())
+    }
+
+    pub fn insert(&mut self, address: SocketAddr, key: &str, packet: Packet, lifetime: Duration) {
+        let now = Instant::now();
+        self.purge(now);
+
+        let key = (address, key.to_string());
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            // Evict the transfer that expires first
+            let oldest = self.entries
+                .iter()
+                .min_by_key(|&(_, &(expires_at, _))| expires_at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(key, (now + lifetime, packet));
+    }
+
+    pub fn remove(&mut self, address: SocketAddr, key: &str) {
+        self.entries.remove(&(address, key.to_string()));
+    }
+
+    fn purge(&mut self, now: Instant) {
+        self.entries.retain(|_, &mut (expires_at, _)| expires_at > now);
+    }
+}
+
+/// Identifies the response to a request by its method, Accept, Uri-Path and
+///   Uri-Query options, and by its payload for FETCH requests.
+pub fn cache_key(request: &Packet) -> String {
+    let mut key = resource_key(request);
+    if let Some(accept) = request.get_accept() {
+        key.push_str(&format!(";{}", accept));
+    }
+    if request.header.code == PacketClass::Request(Requests::Fetch) {
+        key.push('$');
+        push_hex(&mut key, &etag(&request.payload));
+    }
+    key
+}
+
+/// Identifies the resource of a request by its method and its Uri-Path and
+///   Uri-Query options. Each option value is prefixed with its length, so a
+///   value containing a separator cannot pass for several.
+fn resource_key(request: &Packet) -> String {
+    let mut key = request.header.get_code();
+    for &(separator, tp) in &[('/', OptionType::UriPath), ('?', OptionType::UriQuery)] {
+        if let Some(values) = request.get_option(tp) {
+            for value in values.iter() {
+                key.push_str(&format!("{}{}:", separator, value.len()));
+                key.push_str(&String::from_utf8_lossy(value));
+            }
+        }
+    }
+    key
+}
+
+/// Identifies a block-wise request body by the request URI and its Request-Tag
+///   option, or its token if it has none.
+pub fn upload_key(request: &Packet) -> String {
+    let tag = match request.get_option(OptionType::RequestTag) {
+        Some(values) => values.front().cloned().unwrap_or_default(),
+        None => request.get_token().clone(),
+    };
+    let mut key = resource_key(request);
+    key.push('#');
+    push_hex(&mut key, &tag);
+    key
+}
+
+fn push_hex(key: &mut String, bytes: &[u8]) {
+    for byte in bytes {
+        key.push_str(&format!("{:02x}", byte));
+    }
+}
+
+/// Computes an entity tag for a payload.
+pub fn etag(payload: &[u8]) -> Vec<u8> {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(payload);
+    let hash = hasher.finish();
+    (0..8).map(|i| (hash >> (i * 8)) as u8).collect()
+}
+
+/// Returns the response carrying the requested block of the complete response,
+///   or `None` if the block is beyond the end of the payload.
+pub fn slice(response: &Packet, block: BlockValue) -> Option<Packet> {
+    let offset = block.offset();
+    if offset > 0 && offset >= response.payload.len() {
+        return None;
+    }
+
+    let end = ::std::cmp::min(offset + block.size(), response.payload.len());
+    let mut packet = response.clone();
+    packet.payload = response.payload[offset..end].to_vec();
+    let more = end < response.payload.len();
+    if packet.set_block2(BlockValue { more: more, ..block }).is_err() {
+        return None;
+    }
+    if packet.get_option(OptionType::ETag).is_none() {
+        let mut values = LinkedList::new();
+        values.push_back(etag(&response.payload));
+        packet.set_option(OptionType::ETag, values);
+    }
+    Some(packet)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+    use packet::{Packet, PacketClass, Requests, BlockValue, OptionType};
+
+    #[test]
+    fn test_slice() {
+        let mut response = Packet::new();
+        response.payload = (0..40).collect();
+
+        let first = slice(&response, BlockValue::new(0, false, 0).unwrap()).unwrap();
+        assert_eq!(first.payload, (0..16).collect::<Vec<u8>>());
+        assert_eq!(first.get_block2(), BlockValue::new(0, true, 0));
+
+        let last = slice(&response, BlockValue::new(2, false, 0).unwrap()).unwrap();
+        assert_eq!(last.payload, (32..40).collect::<Vec<u8>>());
+        assert_eq!(last.get_block2(), BlockValue::new(2, false, 0));
+        assert_eq!(first.get_option(OptionType::ETag), last.get_option(OptionType::ETag));
+
+        assert!(slice(&response, BlockValue::new(3, false, 0).unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_cache() {
+        let peer = "127.0.0.1:1".parse().unwrap();
+        let mut cache = BlockCache::new(1);
+        cache.insert(peer, "/a", Packet::new(), Duration::new(10, 0));
+        assert!(cache.get(peer, "/a").is_some());
+        cache.insert(peer, "/b", Packet::new(), Duration::new(10, 0));
+        assert!(cache.get(peer, "/a").is_none());
+        assert!(cache.get(peer, "/b").is_some());
+        cache.remove(peer, "/b");
+        assert!(cache.get(peer, "/b").is_none());
+    }
+
+    #[test]
+    fn test_upload_key() {
+        let mut request = Packet::new();
+        request.add_option(OptionType::UriPath, b"config".to_vec());
+        request.set_token(vec![0x0A, 0xFF]);
+        assert_eq!(upload_key(&request), "0.00/6:config#0aff");
+
+        request.add_option(OptionType::RequestTag, vec![0x01]);
+        assert_eq!(upload_key(&request), "0.00/6:config#01");
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let mut request = Packet::new();
+        request.header.code = PacketClass::Request(Requests::Get);
+        request.add_option(OptionType::UriPath, b"a/b".to_vec());
+        let key = cache_key(&request);
+        assert_eq!(key, "0.01/3:a/b");
+
+        let mut split = request.clone();
+        split.clear_option(OptionType::UriPath);
+        split.add_option(OptionType::UriPath, b"a".to_vec());
+        split.add_option(OptionType::UriPath, b"b".to_vec());
+        assert!(cache_key(&split) != key);
+
+        let mut post = request.clone();
+        post.header.code = PacketClass::Request(Requests::Post);
+        assert!(cache_key(&post) != key);
+
+        request.set_accept(50);
+        assert_eq!(cache_key(&request), "0.01/3:a/b;50");
+    }
+
+    #[test]
+    fn test_fetch_cache_key() {
+        let mut request = Packet::new();
+        request.add_option(OptionType::UriPath, b"items".to_vec());
+        request.payload = b"a".to_vec();
+        assert_eq!(cache_key(&request), "0.00/5:items");
+
+        request.header.code = PacketClass::Request(Requests::Fetch);
+        let key = cache_key(&request);
+        assert!(key.starts_with("0.05/5:items$"));
+        request.payload = b"b".to_vec();
+        assert!(cache_key(&request) != key);
+        assert_eq!(upload_key(&request), "0.05/5:items#");
+    }
+}
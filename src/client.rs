@@ -1,20 +1,32 @@
 use std::io::{Result, Error, ErrorKind};
 use std::net::{ToSocketAddrs, SocketAddr, UdpSocket};
-use std::time::Duration;
-use url::{UrlParser, SchemeType};
+use std::time::{Duration, Instant};
 use num;
 use rand::{thread_rng, random, Rng};
-use packet::{Packet, PacketType, OptionType};
+use packet::{Packet, PacketType, PacketClass, OptionType, Requests, Responses, BlockValue,
+             PackageError, class_to_code, DEFAULT_MAX_MESSAGE_SIZE};
+use transmission::TransmissionParameters;
+use block;
 
-const DEFAULT_RECEIVE_TIMEOUT: u64 = 5;  // 5s
+const DEFAULT_RECEIVE_TIMEOUT: u64 = 5;  // 5s, for receive() only
+const DEFAULT_MAX_AGE: u64 = 60;  // 60s
+const FRESHNESS_TIMEOUT: u64 = 128;  // 128s
 
 pub struct CoAPClient {
     socket: UdpSocket,
     peer_addr: SocketAddr,
+    parameters: TransmissionParameters,
+    block_size_exponent: Option<u8>,
+    max_message_size: usize,
+    max_body_size: usize,
 }
 
 impl CoAPClient {
-    /// Create a CoAP client with the peer address.
+    /// Create a CoAP client with the peer address. `receive` gives up after
+    ///   5 seconds unless `set_receive_timeout` is called. Requests wait for
+    ///   their response up to MAX_TRANSMIT_WAIT instead, 93 seconds with the
+    ///   default transmission parameters (it was 5 seconds before
+    ///   retransmission was supported).
     pub fn new<A: ToSocketAddrs>(addr: A) -> Result<CoAPClient> {
         addr.to_socket_addrs().and_then(|mut iter| {
             match iter.next() {
@@ -25,6 +37,10 @@ impl CoAPClient {
                                 Ok(CoAPClient {
                                     socket: s,
                                     peer_addr: SocketAddr::V4(a),
+                                    parameters: TransmissionParameters::default(),
+                                    block_size_exponent: None,
+                                    max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+                                    max_body_size: block::DEFAULT_MAX_BODY_SIZE,
                                 })
                             })
                     })
@@ -36,6 +52,10 @@ impl CoAPClient {
                                 Ok(CoAPClient {
                                     socket: s,
                                     peer_addr: SocketAddr::V6(a),
+                                    parameters: TransmissionParameters::default(),
+                                    block_size_exponent: None,
+                                    max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+                                    max_body_size: block::DEFAULT_MAX_BODY_SIZE,
                                 })
                             })
                     })
@@ -45,67 +65,379 @@ impl CoAPClient {
         })
     }
 
-    /// Execute a request with the coap url and a specific timeout. Default timeout is 5s.
+    /// Execute a GET request with the coap url and a specific timeout. Without a
+    ///   timeout the request gives up after MAX_TRANSMIT_WAIT.
     pub fn request_with_timeout(url: &str, timeout: Option<Duration>) -> Result<Packet> {
-        let mut url_parser = UrlParser::new();
-        url_parser.scheme_type_mapper(Self::coap_scheme_type_mapper);
-
-        match url_parser.parse(url) {
-            Ok(url_params) => {
-                let mut packet = Packet::new();
-                packet.header.set_version(1);
-                packet.header.set_type(PacketType::Confirmable);
-                packet.header.set_code("0.01");
-
-                let message_id = thread_rng().gen_range(0, num::pow(2u32, 16)) as u16;
-                packet.header.set_message_id(message_id);
-
-                let mut token: Vec<u8> = vec![1, 1, 1, 1];
-                for x in token.iter_mut() {
-                    *x = random()
-                }
-                packet.set_token(token.clone());
+        Self::execute(Requests::Get, url, Vec::new(), Vec::new(), timeout)
+    }
+
+    /// Execute a request with the method, the coap url, the payload and extra
+    ///   options, e.g. the Content-Format of the payload.
+    pub fn request(method: Requests,
+                   url: &str,
+                   payload: Vec<u8>,
+                   options: Vec<(OptionType, Vec<u8>)>)
+                   -> Result<Packet> {
+        Self::execute(method, url, payload, options, None)
+    }
+
+    /// Execute a GET request with the coap url.
+    pub fn get(url: &str) -> Result<Packet> {
+        Self::request(Requests::Get, url, Vec::new(), Vec::new())
+    }
+
+    /// Execute a POST request with the coap url and the payload.
+    pub fn post(url: &str, payload: Vec<u8>) -> Result<Packet> {
+        Self::request(Requests::Post, url, payload, Vec::new())
+    }
+
+    /// Execute a PUT request with the coap url and the payload.
+    pub fn put(url: &str, payload: Vec<u8>) -> Result<Packet> {
+        Self::request(Requests::Put, url, payload, Vec::new())
+    }
+
+    /// Execute a DELETE request with the coap url.
+    pub fn delete(url: &str) -> Result<Packet> {
+        Self::request(Requests::Delete, url, Vec::new(), Vec::new())
+    }
+
+    /// Execute a FETCH request with the coap url and the payload selecting
+    ///   what to fetch ([RFC 8132][spec]).
+    ///
+    /// [spec]: https://tools.ietf.org/html/rfc8132
+    pub fn fetch(url: &str, payload: Vec<u8>) -> Result<Packet> {
+        Self::request(Requests::Fetch, url, payload, Vec::new())
+    }
+
+    /// Execute a PATCH request with the coap url and the patch document.
+    pub fn patch(url: &str, payload: Vec<u8>) -> Result<Packet> {
+        Self::request(Requests::Patch, url, payload, Vec::new())
+    }
+
+    /// Execute an iPATCH request with the coap url and the patch document. An
+    ///   iPATCH request is idempotent, unlike a PATCH request.
+    pub fn ipatch(url: &str, payload: Vec<u8>) -> Result<Packet> {
+        Self::request(Requests::IPatch, url, payload, Vec::new())
+    }
+
+    fn execute(method: Requests,
+               url: &str,
+               payload: Vec<u8>,
+               options: Vec<(OptionType, Vec<u8>)>,
+               timeout: Option<Duration>)
+               -> Result<Packet> {
+        // Secure CoAP needs DTLS, which is not supported
+        if url.to_lowercase().starts_with("coaps:") {
+            return Err(Error::new(ErrorKind::InvalidInput, "unsupported scheme"));
+        }
+
+        let mut packet = Packet::new();
+        packet.header.set_version(1);
+        packet.header.set_type(PacketType::Confirmable);
+        packet.header.code = PacketClass::Request(method);
+
+        let message_id = thread_rng().gen_range(0, num::pow(2u32, 16)) as u16;
+        packet.header.set_message_id(message_id);
+
+        let mut token: Vec<u8> = vec![1, 1, 1, 1];
+        for x in token.iter_mut() {
+            *x = random()
+        }
+        packet.set_token(token.clone());
+
+        let (host, port) = match packet.set_uri(url) {
+            Ok(endpoint) => endpoint,
+            Err(_) => return Err(Error::new(ErrorKind::InvalidInput, "url error")),
+        };
+        for (option, value) in options {
+            packet.add_option(option, value);
+        }
+        packet.payload = payload;
+
+        let client = try!(Self::new((&host[..], port)));
+        client.send_request(&packet, timeout)
+    }
+
+    /// Check that the peer is reachable with a CoAP ping, an empty Confirmable
+    ///   message that the peer answers with RST. It is retransmitted like a
+    ///   request. Returns the round-trip time measured from the last
+    ///   transmission, so it leaves out the waits between retransmissions.
+    pub fn ping(&self, timeout: Option<Duration>) -> Result<Duration> {
+        let mut ping = Packet::new();
+        ping.header.set_version(1);
+        ping.header.set_type(PacketType::Confirmable);
+        ping.header.set_message_id(random());
+
+        let (_, sent_at) = try!(self.timed_exchange(&ping, timeout));
+        Ok(sent_at.elapsed())
+    }
 
-                let domain = match url_params.domain() {
-                    Some(d) => d,
-                    None => return Err(Error::new(ErrorKind::InvalidInput, "domain error")),
+    /// Execute a request and wait for the response. Confirmable requests are
+    ///   retransmitted with exponential backoff until they are acknowledged.
+    ///   If the server acknowledges with an empty ACK, the separate response is
+    ///   awaited and acknowledged when it is Confirmable.
+    ///   A payload larger than the block size is sent in blocks (Block1), and a
+    ///   response sent in blocks (Block2) is reassembled transparently, up to
+    ///   the maximum body size, unless the request asks for a later block with
+    ///   its own Block2 option.
+    ///   Gives up with a `TimedOut` error once the timeout or MAX_TRANSMIT_WAIT
+    ///   has passed for one of the exchanges, whichever comes first, and with a
+    ///   `ConnectionRefused` error when the server rejects a request with RST.
+    ///   Malformed messages and ones larger than the maximum message size are
+    ///   ignored while waiting.
+    pub fn send_request(&self, request: &Packet, timeout: Option<Duration>) -> Result<Packet> {
+        let mut request = request.clone();
+        if let Some(size_exponent) = self.block_size_exponent {
+            if request.get_block2().is_none() {
+                let first = BlockValue {
+                    num: 0,
+                    more: false,
+                    size_exponent: size_exponent,
                 };
-                let port = url_params.port_or_default().unwrap();
+                try!(request.set_block2(first).map_err(block_error));
+            }
+        }
+
+        // A request for a later block gets that block only
+        if request.get_block2().map_or(false, |block| block.num > 0) {
+            return self.send_body(&mut request, timeout);
+        }
+
+        let mut response = try!(self.send_body(&mut request, timeout));
+        let mut block = match response.get_block2() {
+            Some(block) if block.num > 0 => {
+                return Err(Error::new(ErrorKind::InvalidData, "unexpected block"))
+            }
+            Some(block) if block.more => block,
+            _ => return Ok(response),
+        };
+
+        // The following blocks are requested without the request body, unless
+        //   it selects the response as for FETCH
+        let fetch = request.header.code == PacketClass::Request(Requests::Fetch);
+        if !fetch || request.get_block1().is_some() {
+            request.payload = Vec::new();
+            request.clear_option(OptionType::Block1);
+            request.clear_option(OptionType::Size1);
+        }
+
+        let too_large = || Error::new(ErrorKind::InvalidData, "response body too large");
+        if response.get_size2().map_or(false, |size| size as usize > self.max_body_size) {
+            return Err(too_large());
+        }
+
+        let etag = response.get_option(OptionType::ETag);
+        let mut payload = response.payload.clone();
+        while block.more {
+            // Ask for the block following the received data, in the block
+            //   size chosen by the server
+            let next = BlockValue {
+                num: block.num + 1,
+                more: false,
+                size_exponent: block.size_exponent,
+            };
+            request.header.set_message_id(random());
+            try!(request.set_block2(next).map_err(block_error));
+
+            let next_response = try!(self.exchange(&request, timeout));
+            block = match next_response.get_block2() {
+                Some(block) if block.offset() == payload.len() => block,
+                _ => return Err(Error::new(ErrorKind::InvalidData, "unexpected block")),
+            };
+            if next_response.get_option(OptionType::ETag) != etag {
+                return Err(Error::new(ErrorKind::InvalidData,
+                                      "resource changed during block-wise transfer"));
+            }
+            if payload.len() + next_response.payload.len() > self.max_body_size {
+                return Err(too_large());
+            }
+            payload.extend_from_slice(&next_response.payload);
+        }
+
+        response.clear_option(OptionType::Block2);
+        response.payload = payload;
+        Ok(response)
+    }
+
+    /// Send the payload of the request, in blocks if it is larger than the block
+    ///   size. Returns the response to the last block, or the first response
+    ///   other than 2.31 Continue, e.g. 4.13 Request Entity Too Large.
+    fn send_body(&self, request: &mut Packet, timeout: Option<Duration>) -> Result<Packet> {
+        let mut size_exponent = self.block_size_exponent.unwrap_or(block::DEFAULT_SIZE_EXPONENT);
+        let body = request.payload.clone();
+        if body.len() <= BlockValue::size_of(size_exponent) {
+            return self.exchange(request, timeout);
+        }
+
+        request.set_size1(body.len() as u32);
+        let mut offset = 0;
+        loop {
+            let size = BlockValue::size_of(size_exponent);
+            let end = ::std::cmp::min(offset + size, body.len());
+            let block = BlockValue {
+                num: (offset / size) as u32,
+                more: end < body.len(),
+                size_exponent: size_exponent,
+            };
+            request.payload = body[offset..end].to_vec();
+            try!(request.set_block1(block).map_err(block_error));
+
+            let response = try!(self.exchange(request, timeout));
+            if !block.more ||
+               response.header.code != PacketClass::Response(Responses::Continue) {
+                return Ok(response);
+            }
+
+            // Continue in the block size chosen by the server
+            if let Some(acknowledged) = response.get_block1() {
+                if acknowledged.size_exponent < size_exponent {
+                    size_exponent = acknowledged.size_exponent;
+                }
+            }
+            offset = end;
+            request.header.set_message_id(random());
+            request.clear_option(OptionType::Size1);
+        }
+    }
+
+    /// Execute one request/response exchange, keeping the receive timeout
+    ///   set for `receive`.
+    fn exchange(&self, request: &Packet, timeout: Option<Duration>) -> Result<Packet> {
+        self.timed_exchange(request, timeout).map(|(response, _)| response)
+    }
+
+    /// Execute one request/response exchange like `exchange`. Also returns
+    ///   when the last transmission of the request was sent.
+    fn timed_exchange(&self,
+                      request: &Packet,
+                      timeout: Option<Duration>)
+                      -> Result<(Packet, Instant)> {
+        let receive_timeout = try!(self.socket.read_timeout());
+        let result = self.transmit(request, timeout);
+        try!(self.set_receive_timeout(receive_timeout));
+        result
+    }
+
+    /// Send the request, retransmitting it when Confirmable, and wait for the
+    ///   response. Returns it with the time of the last transmission.
+    fn transmit(&self, request: &Packet, timeout: Option<Duration>) -> Result<(Packet, Instant)> {
+        let mut wait = self.parameters.max_transmit_wait();
+        if let Some(t) = timeout {
+            if t < wait {
+                wait = t;
+            }
+        }
+        let deadline = Instant::now() + wait;
+
+        let mut awaiting_ack = request.header.get_type() == PacketType::Confirmable;
+        let mut retransmit_timeout = self.parameters.initial_timeout();
+        let mut retransmit_count = 0;
+
+        try!(self.send(request));
+        let mut sent_at = Instant::now();
+        let mut retransmit_at = sent_at + retransmit_timeout;
+
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::new(ErrorKind::TimedOut, "request timed out"));
+            }
 
-                if let Some(path) = url_params.path() {
-                    for p in path.iter() {
-                        packet.add_option(OptionType::UriPath, p.clone().into_bytes().to_vec());
+            if awaiting_ack && now >= retransmit_at {
+                if retransmit_count >= self.parameters.max_retransmit {
+                    return Err(Error::new(ErrorKind::TimedOut, "request timed out"));
+                }
+
+                retransmit_count += 1;
+                retransmit_timeout = retransmit_timeout * 2;
+                debug!("Retransmitting request ({})", retransmit_count);
+                try!(self.send(request));
+                sent_at = Instant::now();
+                retransmit_at = sent_at + retransmit_timeout;
+                continue;
+            }
+
+            let wake_at = if awaiting_ack && retransmit_at < deadline {
+                retransmit_at
+            } else {
+                deadline
+            };
+            try!(self.set_receive_timeout(Some(Self::remaining(wake_at, now))));
+
+            match self.receive() {
+                Ok(response) => {
+                    if Self::is_reset_to(request, &response) ||
+                       Self::is_empty_ack_to(request, &response) {
+                        // Either answers a ping
+                        if request.header.code == PacketClass::Empty {
+                            return Ok((response, sent_at));
+                        }
+                    }
+                    if Self::is_reset_to(request, &response) {
+                        return Err(Error::new(ErrorKind::ConnectionRefused, "request rejected"));
+                    }
+                    if Self::is_empty_ack_to(request, &response) {
+                        debug!("Request acknowledged, waiting for separate response");
+                        awaiting_ack = false;
+                        continue;
                     }
-                };
 
-                let client = try!(Self::new((domain, port)));
-                try!(client.send(&packet));
-
-                try!(client.set_receive_timeout(timeout));
-                match client.receive() {
-                    Ok(receive_packet) => {
-                        if receive_packet.header.get_message_id() == message_id &&
-                           *receive_packet.get_token() == token {
-                            return Ok(receive_packet);
-                        } else {
-                            return Err(Error::new(ErrorKind::Other, "receive invalid data"));
+                    if Self::is_response_to(request, &response) {
+                        if response.header.get_type() == PacketType::Confirmable {
+                            try!(self.acknowledge(&response));
+                        }
+                        // Until an observation request is acknowledged, a
+                        //   notification with its token answers an earlier one
+                        if awaiting_ack && Self::is_notification_to(request, &response) {
+                            debug!("Ignoring notification while awaiting the response");
+                            continue;
                         }
+                        return Ok((response, sent_at));
                     }
-                    Err(e) => Err(e),
+                    debug!("Ignoring unrelated message: {:?}", response);
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock ||
+                              e.kind() == ErrorKind::TimedOut => {}
+                Err(ref e) if e.kind() == ErrorKind::InvalidInput => {
+                    debug!("Ignoring malformed message");
+                }
+                Err(ref e) if e.kind() == ErrorKind::InvalidData => {
+                    debug!("Ignoring oversized message");
                 }
+                Err(e) => return Err(e),
             }
-            Err(_) => Err(Error::new(ErrorKind::InvalidInput, "url error")),
         }
     }
 
-    /// Execute a request with the coap url.
-    pub fn request(url: &str) -> Result<Packet> {
-        Self::request_with_timeout(url, Some(Duration::new(DEFAULT_RECEIVE_TIMEOUT, 0)))
+    /// Register as an observer of the resource requested by the GET request
+    ///   ([RFC 7641][spec]). The returned subscription yields the response to the
+    ///   registration and then every fresh notification of the resource.
+    ///
+    /// [spec]: https://tools.ietf.org/html/rfc7641
+    pub fn observe<'a>(&'a self, mut request: Packet) -> Result<Subscription<'a>> {
+        if request.get_token().is_empty() {
+            let token: Vec<u8> = (0..4).map(|_| random()).collect();
+            request.set_token(token);
+        }
+        request.set_observe(0);
+
+        let response = try!(self.send_request(&request, None));
+        let mut subscription = Subscription {
+            client: self,
+            request: request,
+            pending: None,
+            registered: false,
+            last_sequence: None,
+            last_received: Instant::now(),
+            expires_at: Instant::now(),
+        };
+        subscription.accept(response, true);
+        Ok(subscription)
     }
 
     /// Execute a request.
     pub fn send(&self, packet: &Packet) -> Result<()> {
-        match packet.to_bytes() {
+        match packet.to_bytes_with_limit(self.max_message_size) {
             Ok(bytes) => {
                 let size = try!(self.socket.send_to(&bytes[..], self.peer_addr));
                 if size == bytes.len() {
@@ -118,11 +450,16 @@ impl CoAPClient {
         }
     }
 
-    /// Receive a response.
+    /// Receive a response. A message larger than the maximum message size is
+    ///   reported as an `InvalidData` error, as it may have been truncated.
     pub fn receive(&self) -> Result<Packet> {
-        let mut buf = [0; 1500];
+        // One byte more than the maximum tells a larger message apart
+        let mut buf = vec![0; self.max_message_size + 1];
 
         let (nread, _src) = try!(self.socket.recv_from(&mut buf));
+        if nread > self.max_message_size {
+            return Err(Error::new(ErrorKind::InvalidData, "message too large"));
+        }
         match Packet::from_bytes(&buf[..nread]) {
             Ok(packet) => Ok(packet),
             Err(_) => Err(Error::new(ErrorKind::InvalidInput, "packet error")),
@@ -134,28 +471,317 @@ impl CoAPClient {
         self.socket.set_read_timeout(dur)
     }
 
-    fn coap_scheme_type_mapper(scheme: &str) -> SchemeType {
-        match scheme {
-            "coap" => SchemeType::Relative(5683),
-            _ => SchemeType::NonRelative,
+    /// Set the parameters used to retransmit Confirmable requests.
+    pub fn set_transmission_parameters(&mut self, parameters: TransmissionParameters) {
+        self.parameters = parameters;
+    }
+
+    /// Set the preferred block size for responses sent in blocks, a power of
+    ///   two from 16 to 1024 bytes. Requests then ask the server for blocks of
+    ///   this size (Block2 early negotiation).
+    pub fn set_block_size(&mut self, size: usize) -> Result<()> {
+        match BlockValue::size_exponent_of(size) {
+            Some(size_exponent) => {
+                self.block_size_exponent = Some(size_exponent);
+                Ok(())
+            }
+            None => Err(Error::new(ErrorKind::InvalidInput, "invalid block size")),
+        }
+    }
+
+    /// Set the largest message sent or received, in bytes, 1280 by default.
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.max_message_size = size;
+    }
+
+    /// Set the largest response body reassembled from blocks (Block2), in
+    ///   bytes, 64 KiB by default. A larger response is an `InvalidData` error.
+    pub fn set_max_body_size(&mut self, size: usize) {
+        self.max_body_size = size;
+    }
+
+    /// Get the parameters used to retransmit Confirmable requests.
+    pub fn get_transmission_parameters(&self) -> &TransmissionParameters {
+        &self.parameters
+    }
+
+    /// Send an empty ACK for a Confirmable message.
+    fn acknowledge(&self, packet: &Packet) -> Result<()> {
+        let mut ack = Packet::new();
+        ack.header.set_version(1);
+        ack.header.set_type(PacketType::Acknowledgement);
+        ack.header.set_message_id(packet.header.get_message_id());
+        self.send(&ack)
+    }
+
+    fn is_reset_to(request: &Packet, packet: &Packet) -> bool {
+        packet.header.get_type() == PacketType::Reset &&
+        packet.header.get_message_id() == request.header.get_message_id()
+    }
+
+    fn is_empty_ack_to(request: &Packet, packet: &Packet) -> bool {
+        packet.header.get_type() == PacketType::Acknowledgement &&
+        packet.header.code == PacketClass::Empty &&
+        packet.header.get_message_id() == request.header.get_message_id()
+    }
+
+    fn is_response_to(request: &Packet, response: &Packet) -> bool {
+        if response.header.code == PacketClass::Empty ||
+           *response.get_token() != *request.get_token() {
+            return false;
+        }
+
+        match response.header.get_type() {
+            // A piggybacked response must match the message ID of the request
+            PacketType::Acknowledgement => {
+                response.header.get_message_id() == request.header.get_message_id()
+            }
+            // A separate response is matched by its token only
+            PacketType::Confirmable | PacketType::NonConfirmable => true,
+            _ => false,
+        }
+    }
+
+    fn is_notification_to(request: &Packet, response: &Packet) -> bool {
+        request.get_observe().is_some() && response.get_observe().is_some() &&
+        response.header.get_type() != PacketType::Acknowledgement &&
+        class_to_code(&response.header.code) >> 5 == 2
+    }
+
+    fn remaining(until: Instant, now: Instant) -> Duration {
+        let remaining = until - now;
+        // A zero read timeout is rejected by the socket
+        if remaining < Duration::from_millis(1) {
+            Duration::from_millis(1)
+        } else {
+            remaining
+        }
+    }
+
+    fn reset(&self, packet: &Packet) -> Result<()> {
+        let mut rst = Packet::new();
+        rst.header.set_version(1);
+        rst.header.set_type(PacketType::Reset);
+        rst.header.set_message_id(packet.header.get_message_id());
+        self.send(&rst)
+    }
+}
+
+/// An observation of a resource, see `CoAPClient::observe`.
+///
+/// Iterating blocks until the next fresh notification arrives. Notifications
+///   older than the latest one are dropped, and Confirmable ones are
+///   acknowledged. When the Max-Age of the latest notification expires without
+///   a new one, the client registers again.
+pub struct Subscription<'a> {
+    client: &'a CoAPClient,
+    request: Packet,
+    pending: Option<Packet>,
+    registered: bool,
+    last_sequence: Option<u32>,
+    last_received: Instant,
+    expires_at: Instant,
+}
+
+impl<'a> Subscription<'a> {
+    /// Returns whether the server accepted the registration.
+    pub fn is_registered(&self) -> bool {
+        self.registered
+    }
+
+    /// Cancels the observation by deregistering with Observe=1.
+    pub fn cancel(mut self) -> Result<Packet> {
+        self.registered = false;
+        self.request.header.set_message_id(random());
+        self.request.set_observe(1);
+        self.client.send_request(&self.request, None)
+    }
+
+    /// Waits for the next fresh notification, keeping the receive timeout of
+    ///   the client.
+    fn receive(&mut self) -> Result<Packet> {
+        let receive_timeout = try!(self.client.socket.read_timeout());
+        let result = self.wait();
+        try!(self.client.set_receive_timeout(receive_timeout));
+        result
+    }
+
+    fn wait(&mut self) -> Result<Packet> {
+        loop {
+            let now = Instant::now();
+            if now >= self.expires_at {
+                debug!("Max-Age expired, registering again");
+                self.request.header.set_message_id(random());
+                let response = try!(self.client.send_request(&self.request, None));
+                if self.accept(response, true) {
+                    return Ok(self.pending.take().unwrap());
+                }
+                continue;
+            }
+
+            try!(self.client.set_receive_timeout(Some(CoAPClient::remaining(self.expires_at, now))));
+            let packet = match self.client.receive() {
+                Ok(packet) => packet,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock ||
+                              e.kind() == ErrorKind::TimedOut ||
+                              e.kind() == ErrorKind::InvalidInput ||
+                              e.kind() == ErrorKind::InvalidData => continue,
+                Err(e) => return Err(e),
+            };
+
+            let confirmable = packet.header.get_type() == PacketType::Confirmable;
+            if *packet.get_token() != *self.request.get_token() ||
+               packet.header.code == PacketClass::Empty {
+                // Reject notifications we are not interested in
+                if confirmable {
+                    try!(self.client.reset(&packet));
+                }
+                continue;
+            }
+
+            if confirmable {
+                try!(self.client.acknowledge(&packet));
+            }
+            if self.accept(packet, false) {
+                return Ok(self.pending.take().unwrap());
+            }
         }
     }
+
+    /// Keeps the notification if it is fresh. Returns whether it was kept. A
+    ///   response to a registration renews the observation even when it is not
+    ///   fresh.
+    fn accept(&mut self, packet: Packet, registration: bool) -> bool {
+        let now = Instant::now();
+        let sequence = packet.get_observe();
+
+        match sequence {
+            Some(sequence) => {
+                let fresh = self.last_sequence
+                    .map_or(true, |last| is_fresh(last, self.last_received, sequence, now));
+                if fresh || registration {
+                    self.registered = true;
+                    self.last_received = now;
+                    self.expires_at = now + max_age(&packet);
+                }
+                if !fresh {
+                    debug!("Dropping stale notification {}", sequence);
+                    return false;
+                }
+                self.last_sequence = Some(sequence);
+            }
+            // A response without Observe option ends the observation
+            None => self.registered = false,
+        }
+
+        self.pending = Some(packet);
+        true
+    }
+}
+
+impl<'a> Iterator for Subscription<'a> {
+    type Item = Result<Packet>;
+
+    fn next(&mut self) -> Option<Result<Packet>> {
+        if let Some(packet) = self.pending.take() {
+            return Some(Ok(packet));
+        }
+        if !self.registered {
+            return None;
+        }
+        Some(self.receive())
+    }
+}
+
+fn block_error(_: PackageError) -> Error {
+    Error::new(ErrorKind::InvalidInput, "block number out of range")
+}
+
+fn max_age(packet: &Packet) -> Duration {
+    let seconds = packet.get_max_age().map_or(DEFAULT_MAX_AGE, |seconds| seconds as u64);
+    Duration::new(seconds, 0)
+}
+
+/// The freshness rule of RFC 7641 section 3.4: a notification is newer than
+///   the previous one if its sequence number is larger (in serial number
+///   arithmetic), or if more than 128 seconds have passed.
+fn is_fresh(v1: u32, t1: Instant, v2: u32, t2: Instant) -> bool {
+    (v1 < v2 && v2 - v1 < 1 << 23) || (v1 > v2 && v1 - v2 > 1 << 23) ||
+    t2 > t1 + Duration::new(FRESHNESS_TIMEOUT, 0)
 }
 
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use std::time::Duration;
+    use std::thread;
+    use std::time::{Duration, Instant};
     use std::io::ErrorKind;
-    use packet::Packet;
+    use std::net::UdpSocket;
+    use packet::{Packet, PacketType, PacketClass, Requests, Responses, OptionType,
+                 auto_response};
     use server::CoAPServer;
+    use transmission::TransmissionParameters;
 
     #[test]
     fn test_request_error_url() {
-        assert!(CoAPClient::request("http://127.0.0.1").is_err());
-        assert!(CoAPClient::request("coap://127.0.0.").is_err());
-        assert!(CoAPClient::request("127.0.0.1").is_err());
+        assert!(CoAPClient::get("http://127.0.0.1").is_err());
+        assert!(CoAPClient::get("coap://127.0.0.").is_err());
+        assert!(CoAPClient::get("127.0.0.1").is_err());
+    }
+
+    fn method_handler(request: Packet, response: Option<Packet>) -> Option<Packet> {
+        response.map(|mut packet| {
+            let code = match request.header.code {
+                PacketClass::Request(Requests::Get) |
+                PacketClass::Request(Requests::Fetch) => Responses::Content,
+                PacketClass::Request(Requests::Post) => Responses::Created,
+                PacketClass::Request(Requests::Put) |
+                PacketClass::Request(Requests::Patch) |
+                PacketClass::Request(Requests::IPatch) => Responses::Changed,
+                _ => Responses::Deleted,
+            };
+            packet.header.code = PacketClass::Response(code);
+            packet.set_payload(request.payload.clone());
+            if let Some(format) = request.get_option(OptionType::ContentFormat) {
+                packet.set_option(OptionType::ContentFormat, format);
+            }
+            packet
+        })
+    }
+
+    #[test]
+    fn test_request_methods() {
+        let mut server = CoAPServer::new("127.0.0.1:5701").unwrap();
+        server.handle(method_handler).unwrap();
+        let url = "coap://127.0.0.1:5701/resource";
+
+        let response = CoAPClient::get(url).unwrap();
+        assert_eq!(response.header.code, PacketClass::Response(Responses::Content));
+        let response = CoAPClient::post(url, b"created".to_vec()).unwrap();
+        assert_eq!(response.header.code, PacketClass::Response(Responses::Created));
+        assert_eq!(response.payload, b"created".to_vec());
+        let response = CoAPClient::put(url, b"changed".to_vec()).unwrap();
+        assert_eq!(response.header.code, PacketClass::Response(Responses::Changed));
+        assert_eq!(response.payload, b"changed".to_vec());
+        let response = CoAPClient::delete(url).unwrap();
+        assert_eq!(response.header.code, PacketClass::Response(Responses::Deleted));
+        let response = CoAPClient::fetch(url, b"query".to_vec()).unwrap();
+        assert_eq!(response.header.code, PacketClass::Response(Responses::Content));
+        assert_eq!(response.payload, b"query".to_vec());
+        let response = CoAPClient::patch(url, b"patch".to_vec()).unwrap();
+        assert_eq!(response.header.code, PacketClass::Response(Responses::Changed));
+        let response = CoAPClient::ipatch(url, b"patch".to_vec()).unwrap();
+        assert_eq!(response.header.code, PacketClass::Response(Responses::Changed));
+
+        let response = CoAPClient::request(Requests::Put,
+                                           url,
+                                           b"{}".to_vec(),
+                                           vec![(OptionType::ContentFormat, vec![50])])
+            .unwrap();
+        assert_eq!(response.header.code, PacketClass::Response(Responses::Changed));
+        assert_eq!(response.get_option(OptionType::ContentFormat).unwrap().front(),
+                   Some(&vec![50]));
     }
 
     fn request_handler(_: Packet, _: Option<Packet>) -> Option<Packet> {
@@ -170,10 +796,394 @@ mod test {
         let error = CoAPClient::request_with_timeout("coap://127.0.0.1:5684/Rust",
                                                      Some(Duration::new(1, 0)))
             .unwrap_err();
-        if cfg!(windows) {
-            assert_eq!(error.kind(), ErrorKind::TimedOut);
-        } else {
-            assert_eq!(error.kind(), ErrorKind::WouldBlock);
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+    }
+
+    fn fast_parameters() -> TransmissionParameters {
+        let mut parameters = TransmissionParameters::new();
+        parameters.ack_timeout = Duration::from_millis(100);
+        parameters.ack_random_factor = 1.0;
+        parameters.max_retransmit = 2;
+        parameters
+    }
+
+    fn confirmable_request() -> Packet {
+        let mut packet = Packet::new();
+        packet.header.set_version(1);
+        packet.header.set_type(PacketType::Confirmable);
+        packet.header.set_code("0.01");
+        packet.header.set_message_id(7);
+        packet.set_token(vec![0x51, 0x55, 0x77, 0xE8]);
+        packet
+    }
+
+    #[test]
+    fn test_request_retransmission() {
+        let server = UdpSocket::bind("127.0.0.1:5685").unwrap();
+        let server_thread = thread::spawn(move || {
+            let mut buf = [0; 1500];
+            // Drop the first transmission, answer the retransmission
+            server.recv_from(&mut buf).unwrap();
+            let (nread, src) = server.recv_from(&mut buf).unwrap();
+            let request = Packet::from_bytes(&buf[..nread]).unwrap();
+            let response = auto_response(&request).unwrap();
+            server.send_to(&response.to_bytes().unwrap()[..], src).unwrap();
+        });
+
+        let mut client = CoAPClient::new("127.0.0.1:5685").unwrap();
+        client.set_transmission_parameters(fast_parameters());
+        let response = client.send_request(&confirmable_request(), None).unwrap();
+        assert_eq!(response.header.get_message_id(), 7);
+        assert_eq!(client.socket.read_timeout().unwrap(),
+                   Some(Duration::new(DEFAULT_RECEIVE_TIMEOUT, 0)));
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_request_max_retransmit() {
+        let server = UdpSocket::bind("127.0.0.1:5686").unwrap();
+        server.set_read_timeout(Some(Duration::new(2, 0))).unwrap();
+
+        let mut client = CoAPClient::new("127.0.0.1:5686").unwrap();
+        client.set_transmission_parameters(fast_parameters());
+        let error = client.send_request(&confirmable_request(), None).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+
+        // The initial transmission and two retransmissions
+        let mut buf = [0; 1500];
+        for _ in 0..3 {
+            server.recv_from(&mut buf).unwrap();
+        }
+        assert!(server.recv_from(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_receive_oversized() {
+        let server = UdpSocket::bind("127.0.0.1:5706").unwrap();
+        let server_thread = thread::spawn(move || {
+            let mut buf = [0; 1500];
+            for _ in 0..2 {
+                let (nread, src) = server.recv_from(&mut buf).unwrap();
+                let request = Packet::from_bytes(&buf[..nread]).unwrap();
+                let mut response = auto_response(&request).unwrap();
+                response.set_payload(vec![0; 200]);
+                server.send_to(&response.to_bytes().unwrap()[..], src).unwrap();
+            }
+        });
+
+        // A request skips the response like any malformed message
+        let mut client = CoAPClient::new("127.0.0.1:5706").unwrap();
+        client.set_max_message_size(100);
+        let timeout = Some(Duration::from_millis(300));
+        let error = client.send_request(&confirmable_request(), timeout).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+
+        client.send(&confirmable_request()).unwrap();
+        assert_eq!(client.receive().unwrap_err().kind(), ErrorKind::InvalidData);
+        server_thread.join().unwrap();
+
+        let mut request = confirmable_request();
+        request.set_payload(vec![0; 100]);
+        assert_eq!(client.send(&request).unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_ping() {
+        let mut server = CoAPServer::new("127.0.0.1:5708").unwrap();
+        server.handle(method_handler).unwrap();
+        let client = CoAPClient::new("127.0.0.1:5708").unwrap();
+        let rtt = client.ping(None).unwrap();
+        assert!(rtt < Duration::new(2, 0));
+
+        // The ping is retransmitted until it is answered
+        let server = UdpSocket::bind("127.0.0.1:5709").unwrap();
+        let server_thread = thread::spawn(move || {
+            let mut buf = [0; 1500];
+            let (nread, _) = server.recv_from(&mut buf).unwrap();
+            let ping = Packet::from_bytes(&buf[..nread]).unwrap();
+            assert_eq!(ping.header.get_type(), PacketType::Confirmable);
+            assert_eq!(ping.header.code, PacketClass::Empty);
+
+            let (nread, src) = server.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..nread], &ping.to_bytes().unwrap()[..]);
+            let mut reset = Packet::new();
+            reset.header.set_version(1);
+            reset.header.set_type(PacketType::Reset);
+            reset.header.set_message_id(ping.header.get_message_id());
+            server.send_to(&reset.to_bytes().unwrap()[..], src).unwrap();
+
+            // A request rejected with RST
+            let (nread, src) = server.recv_from(&mut buf).unwrap();
+            let request = Packet::from_bytes(&buf[..nread]).unwrap();
+            reset.header.set_message_id(request.header.get_message_id());
+            server.send_to(&reset.to_bytes().unwrap()[..], src).unwrap();
+        });
+
+        let mut client = CoAPClient::new("127.0.0.1:5709").unwrap();
+        client.set_transmission_parameters(fast_parameters());
+        // The wait before the retransmission is not part of the round trip
+        let rtt = client.ping(None).unwrap();
+        assert!(rtt < Duration::from_millis(100));
+        let error = client.send_request(&confirmable_request(), None).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+        server_thread.join().unwrap();
+
+        let client = CoAPClient::new("127.0.0.1:5710").unwrap();
+        let error = client.ping(Some(Duration::from_millis(200))).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_separate_response() {
+        let server = UdpSocket::bind("127.0.0.1:5687").unwrap();
+        let server_thread = thread::spawn(move || {
+            let mut buf = [0; 1500];
+            let (nread, src) = server.recv_from(&mut buf).unwrap();
+            let request = Packet::from_bytes(&buf[..nread]).unwrap();
+
+            let mut ack = Packet::new();
+            ack.header.set_version(1);
+            ack.header.set_type(PacketType::Acknowledgement);
+            ack.header.set_message_id(request.header.get_message_id());
+            server.send_to(&ack.to_bytes().unwrap()[..], src).unwrap();
+
+            let mut response = auto_response(&request).unwrap();
+            response.header.set_type(PacketType::Confirmable);
+            response.header.set_message_id(100);
+            response.set_payload(b"separate".to_vec());
+            server.send_to(&response.to_bytes().unwrap()[..], src).unwrap();
+
+            let (nread, _) = server.recv_from(&mut buf).unwrap();
+            Packet::from_bytes(&buf[..nread]).unwrap()
+        });
+
+        let mut client = CoAPClient::new("127.0.0.1:5687").unwrap();
+        client.set_transmission_parameters(fast_parameters());
+        let response = client.send_request(&confirmable_request(), None).unwrap();
+        assert_eq!(response.payload, b"separate".to_vec());
+
+        let ack = server_thread.join().unwrap();
+        assert_eq!(ack.header.get_type(), PacketType::Acknowledgement);
+        assert_eq!(ack.header.code, PacketClass::Empty);
+        assert_eq!(ack.header.get_message_id(), 100);
+    }
+
+    #[test]
+    fn test_freshness() {
+        let t1 = Instant::now();
+        assert!(is_fresh(1, t1, 2, t1));
+        assert!(!is_fresh(2, t1, 1, t1));
+        assert!(!is_fresh(2, t1, 2, t1));
+        assert!(is_fresh(0xFFFFFF, t1, 0, t1));
+        assert!(!is_fresh(0, t1, 0xFFFFFF, t1));
+        assert!(is_fresh(2, t1, 1, t1 + Duration::new(129, 0)));
+    }
+
+    fn observe_response(request: &Packet,
+                        message_type: PacketType,
+                        message_id: u16,
+                        sequence: Option<u8>)
+                        -> Vec<u8> {
+        let mut packet = auto_response(request).unwrap();
+        packet.header.set_type(message_type);
+        packet.header.set_message_id(message_id);
+        if let Some(sequence) = sequence {
+            packet.add_option(OptionType::Observe, vec![sequence]);
+            packet.add_option(OptionType::MaxAge, vec![1]);
         }
+        packet.set_payload(vec![sequence.unwrap_or(0)]);
+        packet.to_bytes().unwrap()
+    }
+
+    #[test]
+    fn test_observe() {
+        let server = UdpSocket::bind("127.0.0.1:5696").unwrap();
+        let server_thread = thread::spawn(move || {
+            let mut buf = [0; 1500];
+            let mut receive = || {
+                let (nread, src) = server.recv_from(&mut buf).unwrap();
+                (Packet::from_bytes(&buf[..nread]).unwrap(), src)
+            };
+
+            let (request, src) = receive();
+            assert_eq!(*request.get_option(OptionType::Observe).unwrap().front().unwrap(),
+                       Vec::<u8>::new());
+            let message_id = request.header.get_message_id();
+            server.send_to(&observe_response(&request, PacketType::Acknowledgement,
+                                             message_id, Some(5))[..], src).unwrap();
+            // A stale notification, then a fresh Confirmable one
+            server.send_to(&observe_response(&request, PacketType::NonConfirmable,
+                                             100, Some(3))[..], src).unwrap();
+            server.send_to(&observe_response(&request, PacketType::Confirmable,
+                                             101, Some(6))[..], src).unwrap();
+
+            let (ack, _) = receive();
+            assert_eq!(ack.header.get_type(), PacketType::Acknowledgement);
+            assert_eq!(ack.header.get_message_id(), 101);
+
+            // Registering again after Max-Age
+            let (request, src) = receive();
+            assert_eq!(request.header.code, PacketClass::Request(Requests::Get));
+            let message_id = request.header.get_message_id();
+            server.send_to(&observe_response(&request, PacketType::Acknowledgement,
+                                             message_id, Some(7))[..], src).unwrap();
+
+            let (request, src) = receive();
+            assert_eq!(*request.get_option(OptionType::Observe).unwrap().front().unwrap(),
+                       vec![1]);
+            let message_id = request.header.get_message_id();
+            server.send_to(&observe_response(&request, PacketType::Acknowledgement,
+                                             message_id, None)[..], src).unwrap();
+        });
+
+        let client = CoAPClient::new("127.0.0.1:5696").unwrap();
+        let mut subscription = client.observe(confirmable_request()).unwrap();
+        assert!(subscription.is_registered());
+        let payloads: Vec<Vec<u8>> = subscription.by_ref()
+            .take(3)
+            .map(|n| n.unwrap().payload)
+            .collect();
+        assert_eq!(payloads, vec![vec![5], vec![6], vec![7]]);
+
+        let response = subscription.cancel().unwrap();
+        assert!(response.get_option(OptionType::Observe).is_none());
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_oversized_stray_message() {
+        let server = UdpSocket::bind("127.0.0.1:5713").unwrap();
+        let server_thread = thread::spawn(move || {
+            let mut buf = [0; 1500];
+            let (nread, src) = server.recv_from(&mut buf).unwrap();
+            let request = Packet::from_bytes(&buf[..nread]).unwrap();
+            server.send_to(&[0x40; DEFAULT_MAX_MESSAGE_SIZE + 1][..], src).unwrap();
+            let response = auto_response(&request).unwrap();
+            server.send_to(&response.to_bytes().unwrap()[..], src).unwrap();
+        });
+
+        let client = CoAPClient::new("127.0.0.1:5713").unwrap();
+        let response = client.send_request(&confirmable_request(), None).unwrap();
+        assert_eq!(response.header.code, PacketClass::Response(Responses::Content));
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_endless_blocks() {
+        let server = UdpSocket::bind("127.0.0.1:5714").unwrap();
+        server.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        let server_thread = thread::spawn(move || {
+            let mut buf = [0; 1500];
+            let mut requests = 0;
+            while let Ok((nread, src)) = server.recv_from(&mut buf) {
+                requests += 1;
+                let request = Packet::from_bytes(&buf[..nread]).unwrap();
+                let num = request.get_block2().map_or(0, |block| block.num);
+                let mut response = auto_response(&request).unwrap();
+                response.set_block2(BlockValue::new(num, true, 0).unwrap()).unwrap();
+                if request.get_option(OptionType::UriPath).is_some() {
+                    response.set_size2(1000);
+                }
+                response.set_payload(vec![0; 16]);
+                server.send_to(&response.to_bytes().unwrap()[..], src).unwrap();
+            }
+            requests
+        });
+
+        let mut client = CoAPClient::new("127.0.0.1:5714").unwrap();
+        client.set_max_body_size(64);
+        let error = client.send_request(&confirmable_request(), None).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        // An announced size above the limit fails on the first block
+        let mut request = confirmable_request();
+        request.add_option(OptionType::UriPath, b"sized".to_vec());
+        let error = client.send_request(&request, None).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(server_thread.join().unwrap(), 6);
+    }
+
+    #[test]
+    fn test_unexpected_first_block() {
+        let server = UdpSocket::bind("127.0.0.1:5712").unwrap();
+        let server_thread = thread::spawn(move || {
+            let mut buf = [0; 1500];
+            let (nread, src) = server.recv_from(&mut buf).unwrap();
+            let request = Packet::from_bytes(&buf[..nread]).unwrap();
+            let mut response = auto_response(&request).unwrap();
+            response.set_block2(BlockValue::new(1, true, 0).unwrap()).unwrap();
+            response.set_payload(vec![0; 16]);
+            server.send_to(&response.to_bytes().unwrap()[..], src).unwrap();
+        });
+
+        let client = CoAPClient::new("127.0.0.1:5712").unwrap();
+        let error = client.send_request(&confirmable_request(), None).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_observe_unchanged_registration() {
+        let server = UdpSocket::bind("127.0.0.1:5711").unwrap();
+        let server_thread = thread::spawn(move || {
+            let mut buf = [0; 1500];
+            let (nread, src) = server.recv_from(&mut buf).unwrap();
+            let request = Packet::from_bytes(&buf[..nread]).unwrap();
+            let message_id = request.header.get_message_id();
+            server.send_to(&observe_response(&request, PacketType::Acknowledgement,
+                                             message_id, Some(5))[..], src).unwrap();
+
+            // Registering again after Max-Age, answered with the same sequence
+            let (nread, src) = server.recv_from(&mut buf).unwrap();
+            let request = Packet::from_bytes(&buf[..nread]).unwrap();
+            let message_id = request.header.get_message_id();
+            server.send_to(&observe_response(&request, PacketType::Acknowledgement,
+                                             message_id, Some(5))[..], src).unwrap();
+
+            // The registration is renewed, so the client does not register again
+            server.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+            assert!(server.recv_from(&mut buf).is_err());
+            server.send_to(&observe_response(&request, PacketType::NonConfirmable,
+                                             100, Some(6))[..], src).unwrap();
+        });
+
+        let client = CoAPClient::new("127.0.0.1:5711").unwrap();
+        let subscription = client.observe(confirmable_request()).unwrap();
+        let payloads: Vec<Vec<u8>> = subscription.take(2).map(|n| n.unwrap().payload).collect();
+        assert_eq!(payloads, vec![vec![5], vec![6]]);
+        server_thread.join().unwrap();
+
+        // The subscription leaves the receive timeout as it was
+        assert_eq!(client.socket.read_timeout().unwrap(),
+                   Some(Duration::new(DEFAULT_RECEIVE_TIMEOUT, 0)));
+    }
+
+    #[test]
+    fn test_notification_during_registration() {
+        let server = UdpSocket::bind("127.0.0.1:5715").unwrap();
+        let server_thread = thread::spawn(move || {
+            let mut buf = [0; 1500];
+            let (nread, src) = server.recv_from(&mut buf).unwrap();
+            let request = Packet::from_bytes(&buf[..nread]).unwrap();
+            let message_id = request.header.get_message_id();
+            server.send_to(&observe_response(&request, PacketType::Acknowledgement,
+                                             message_id, Some(5))[..], src).unwrap();
+
+            // A notification crosses the registration request after Max-Age
+            let (nread, src) = server.recv_from(&mut buf).unwrap();
+            let request = Packet::from_bytes(&buf[..nread]).unwrap();
+            server.send_to(&observe_response(&request, PacketType::NonConfirmable,
+                                             100, Some(6))[..], src).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            let message_id = request.header.get_message_id();
+            server.send_to(&observe_response(&request, PacketType::Acknowledgement,
+                                             message_id, Some(7))[..], src).unwrap();
+        });
+
+        let client = CoAPClient::new("127.0.0.1:5715").unwrap();
+        let subscription = client.observe(confirmable_request()).unwrap();
+        let payloads: Vec<Vec<u8>> = subscription.take(2).map(|n| n.unwrap().payload).collect();
+        assert_eq!(payloads, vec![vec![5], vec![7]]);
+        server_thread.join().unwrap();
     }
 }
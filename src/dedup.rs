@@ -0,0 +1,162 @@
+//! Detection of duplicate messages ([RFC 7252 section 4.5][spec]).
+//!
+//! [spec]: https://tools.ietf.org/html/rfc7252#section-4.5
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use packet::Packet;
+
+pub const DEFAULT_CAPACITY: usize = 4096;
+
+type MessageKey = (SocketAddr, u16);
+
+#[derive(Debug, PartialEq)]
+pub enum MessageStatus {
+    /// The message was not seen before.
+    New,
+    /// The message was seen before, with the response sent for it if any.
+    Duplicate(Option<Packet>),
+}
+
+struct CacheEntry {
+    sequence: u64,
+    expires_at: Instant,
+    response: Option<Packet>,
+}
+
+/// A bounded cache of the recently received message IDs of every peer.
+pub struct MessageCache {
+    entries: HashMap<MessageKey, CacheEntry>,
+    order: VecDeque<(MessageKey, u64)>,
+    capacity: usize,
+    sequence: u64,
+}
+
+impl MessageCache {
+    /// Creates a cache holding at most `capacity` messages. When the cache is
+    ///   full, the oldest message is evicted first.
+    pub fn new(capacity: usize) -> MessageCache {
+        MessageCache {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            capacity: capacity,
+            sequence: 0,
+        }
+    }
+
+    /// Checks whether the message from the peer is a duplicate, and records it
+    ///   for `lifetime` if it is not.
+    pub fn check(&mut self, address: SocketAddr, message_id: u16, lifetime: Duration) -> MessageStatus {
+        let now = Instant::now();
+        self.purge(now);
+
+        let key = (address, message_id);
+        if let Some(entry) = self.entries.get(&key) {
+            if entry.expires_at > now {
+                return MessageStatus::Duplicate(entry.response.clone());
+            }
+        }
+
+        while self.entries.len() >= self.capacity && !self.order.is_empty() {
+            self.evict_oldest();
+        }
+
+        self.sequence += 1;
+        self.entries.insert(key,
+                            CacheEntry {
+                                sequence: self.sequence,
+                                expires_at: now + lifetime,
+                                response: None,
+                            });
+        self.order.push_back((key, self.sequence));
+        MessageStatus::New
+    }
+
+    /// Records the response sent for a message, to be resent for its duplicates.
+    pub fn set_response(&mut self, address: SocketAddr, message_id: u16, response: Packet) {
+        if let Some(entry) = self.entries.get_mut(&(address, message_id)) {
+            entry.response = Some(response);
+        }
+    }
+
+    fn purge(&mut self, now: Instant) {
+        loop {
+            let expired = match self.order.front() {
+                Some(&(key, sequence)) => {
+                    match self.entries.get(&key) {
+                        Some(entry) => entry.sequence != sequence || entry.expires_at <= now,
+                        None => true,
+                    }
+                }
+                None => false,
+            };
+            if !expired {
+                break;
+            }
+            self.evict_oldest();
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some((key, sequence)) = self.order.pop_front() {
+            let current = self.entries.get(&key).map_or(false, |e| e.sequence == sequence);
+            if current {
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::thread;
+    use std::time::Duration;
+    use packet::Packet;
+
+    fn peer(port: u16) -> SocketAddr {
+        format!("127.0.0.1:{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn test_duplicate_detection() {
+        let mut cache = MessageCache::new(16);
+        let lifetime = Duration::new(10, 0);
+
+        assert_eq!(cache.check(peer(1), 1, lifetime), MessageStatus::New);
+        assert_eq!(cache.check(peer(1), 1, lifetime), MessageStatus::Duplicate(None));
+        assert_eq!(cache.check(peer(2), 1, lifetime), MessageStatus::New);
+
+        let mut response = Packet::new();
+        response.set_payload(b"cached".to_vec());
+        cache.set_response(peer(1), 1, response);
+        match cache.check(peer(1), 1, lifetime) {
+            MessageStatus::Duplicate(Some(packet)) => assert_eq!(packet.payload, b"cached".to_vec()),
+            _ => panic!("expected a cached response"),
+        }
+    }
+
+    #[test]
+    fn test_expiration() {
+        let mut cache = MessageCache::new(16);
+        assert_eq!(cache.check(peer(1), 1, Duration::from_millis(10)), MessageStatus::New);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(cache.check(peer(1), 1, Duration::from_millis(10)), MessageStatus::New);
+        assert_eq!(cache.entries.len(), 1);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut cache = MessageCache::new(4);
+        let lifetime = Duration::new(10, 0);
+        for message_id in 0..10 {
+            assert_eq!(cache.check(peer(1), message_id, lifetime), MessageStatus::New);
+        }
+        assert_eq!(cache.entries.len(), 4);
+        assert_eq!(cache.check(peer(1), 0, lifetime), MessageStatus::New);
+        assert_eq!(cache.check(peer(1), 9, lifetime), MessageStatus::Duplicate(None));
+    }
+}
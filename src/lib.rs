@@ -5,6 +5,9 @@
 //!
 //! [spec]: https://tools.ietf.org/html/rfc7252
 //!
+//! The client and the server need the default `std` feature. Without it only
+//!   the `packet` module is built, for `no_std` targets with `alloc`.
+//!
 //! # Installation
 //!
 //! First add this to your `Cargo.toml`:
@@ -28,6 +31,7 @@
 
 //! use std::io;
 //! use coap::packet::*;
+//! # #[cfg(feature = "std")]
 //! use coap::{CoAPServer, CoAPClient};
 
 //! fn request_handler(req: Packet, resp: Option<Packet>) -> Option<Packet> {
@@ -35,6 +39,8 @@
 //!     None
 //! }
 
+//! # #[cfg(not(feature = "std"))] fn main() {}
+//! # #[cfg(feature = "std")]
 //! fn main() {
 //! 	let addr = "127.0.0.1:5683";
 //!
@@ -55,33 +61,56 @@
 //! extern crate coap;
 //!
 //! use coap::packet::*;
+//! # #[cfg(feature = "std")]
 //! use coap::CoAPClient;
 //!
+//! # #[cfg(not(feature = "std"))] fn main() {}
+//! # #[cfg(feature = "std")]
 //! fn main() {
 //! 	let url = "coap://127.0.0.1:5683/Rust";
 //! 	println!("Client request: {}", url);
 //!
-//! 	let response: Packet = CoAPClient::request(url).unwrap();
+//! 	let response: Packet = CoAPClient::get(url).unwrap();
 //! 	println!("Server reply: {}", String::from_utf8(response.payload).unwrap());
 //! }
 //! ```
 
-extern crate bincode;
-extern crate rustc_serialize;
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[macro_use]
+extern crate alloc;
+#[cfg(feature = "std")]
 extern crate mio;
+#[cfg(feature = "std")]
 extern crate threadpool;
-extern crate url;
+#[cfg(feature = "std")]
 extern crate num;
+#[cfg(feature = "std")]
 extern crate rand;
 #[cfg(test)]
 extern crate quickcheck;
 
+#[cfg(feature = "std")]
 #[macro_use]
 extern crate log;
 
+#[cfg(feature = "std")]
 pub use server::CoAPServer;
+#[cfg(feature = "std")]
 pub use client::CoAPClient;
 
 pub mod packet;
+#[cfg(feature = "std")]
 pub mod client;
+#[cfg(feature = "std")]
 pub mod server;
+#[cfg(feature = "std")]
+pub mod transmission;
+#[cfg(feature = "std")]
+pub mod router;
+#[cfg(feature = "std")]
+mod dedup;
+#[cfg(feature = "std")]
+mod observe;
+#[cfg(feature = "std")]
+mod block;
@@ -0,0 +1,291 @@
+//! Bookkeeping of the observers of resources ([RFC 7641][spec]).
+//!
+//! [spec]: https://tools.ietf.org/html/rfc7641
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+
+/// Every this many notifications one is sent as Confirmable, to check that
+///   the observer is still interested.
+pub const CONFIRMABLE_INTERVAL: u32 = 10;
+
+const SEQUENCE_MASK: u32 = 0xFFFFFF;
+
+/// The number of recent notifications per observer whose message IDs are
+///   kept to match a Reset.
+const RECENT_MESSAGES: usize = 16;
+
+struct Observer {
+    address: SocketAddr,
+    token: Vec<u8>,
+    recent_message_ids: VecDeque<u16>,
+    unacknowledged: Vec<u16>,
+    non_confirmable_count: u32,
+}
+
+impl Observer {
+    fn was_sent(&self, message_id: u16) -> bool {
+        self.recent_message_ids.contains(&message_id) || self.unacknowledged.contains(&message_id)
+    }
+}
+
+struct Resource {
+    sequence: u32,
+    observers: Vec<Observer>,
+}
+
+/// A notification to send to one observer.
+#[derive(Debug, PartialEq)]
+pub struct Notification {
+    pub address: SocketAddr,
+    pub token: Vec<u8>,
+    pub message_id: u16,
+    pub sequence: u32,
+    pub confirmable: bool,
+}
+
+/// The observers of every resource, keyed by the resource path.
+pub struct ObserverRegistry {
+    resources: HashMap<String, Resource>,
+}
+
+impl ObserverRegistry {
+    pub fn new() -> ObserverRegistry {
+        ObserverRegistry { resources: HashMap::new() }
+    }
+
+    /// Adds an observer of the resource, replacing an existing registration of
+    ///   the same endpoint and token. Returns the current sequence number,
+    ///   which keeps rising across registrations.
+    pub fn register(&mut self, path: &str, address: SocketAddr, token: Vec<u8>) -> u32 {
+        let key = normalize_path(path);
+        for (_, resource) in self.resources.iter_mut().filter(|&(path, _)| *path != key) {
+            resource.observers.retain(|o| o.address != address || o.token != token);
+        }
+        self.resources.retain(|_, resource| !resource.observers.is_empty());
+
+        let resource = self.resources.entry(key).or_insert(Resource {
+            sequence: 0,
+            observers: Vec::new(),
+        });
+        if !resource.observers.iter().any(|o| o.address == address && o.token == token) {
+            resource.observers.push(Observer {
+                address: address,
+                token: token,
+                recent_message_ids: VecDeque::new(),
+                unacknowledged: Vec::new(),
+                non_confirmable_count: 0,
+            });
+        }
+        resource.sequence
+    }
+
+    /// Removes the observations of the endpoint with the token.
+    pub fn deregister(&mut self, address: SocketAddr, token: &[u8]) {
+        self.remove_observers(|o| o.address == address && &o.token[..] == token);
+    }
+
+    /// Removes the observer that was sent the notification with the message ID,
+    ///   when the notification was rejected or timed out.
+    pub fn remove_by_message(&mut self, address: SocketAddr, message_id: u16) {
+        self.remove_observers(|o| o.address == address && o.was_sent(message_id));
+    }
+
+    /// Marks the Confirmable notification with the message ID as acknowledged.
+    pub fn acknowledge(&mut self, address: SocketAddr, message_id: u16) {
+        for resource in self.resources.values_mut() {
+            for observer in resource.observers.iter_mut().filter(|o| o.address == address) {
+                observer.unacknowledged.retain(|&id| id != message_id);
+            }
+        }
+    }
+
+    /// Removes all observers of the resource.
+    pub fn remove_all(&mut self, path: &str) {
+        self.resources.remove(&normalize_path(path));
+    }
+
+    /// Returns the notifications of a new state of the resource, one for every
+    ///   observer, with the next sequence number. While a Confirmable
+    ///   notification to an observer is unacknowledged, the following ones are
+    ///   Confirmable as well ([RFC 7641, section 4.5.2][spec]).
+    ///
+    /// [spec]: https://tools.ietf.org/html/rfc7641#section-4.5.2
+    pub fn notify<F>(&mut self, path: &str, mut next_message_id: F) -> Vec<Notification>
+        where F: FnMut() -> u16
+    {
+        let resource = match self.resources.get_mut(&normalize_path(path)) {
+            Some(resource) => resource,
+            None => return Vec::new(),
+        };
+
+        resource.sequence = (resource.sequence + 1) & SEQUENCE_MASK;
+        let sequence = resource.sequence;
+
+        resource.observers
+            .iter_mut()
+            .map(|observer| {
+                let message_id = next_message_id();
+                let confirmable = !observer.unacknowledged.is_empty() ||
+                                  observer.non_confirmable_count + 1 >= CONFIRMABLE_INTERVAL;
+                if confirmable {
+                    observer.non_confirmable_count = 0;
+                    observer.unacknowledged.push(message_id);
+                } else {
+                    observer.non_confirmable_count += 1;
+                }
+                if observer.recent_message_ids.len() == RECENT_MESSAGES {
+                    observer.recent_message_ids.pop_front();
+                }
+                observer.recent_message_ids.push_back(message_id);
+
+                Notification {
+                    address: observer.address,
+                    token: observer.token.clone(),
+                    message_id: message_id,
+                    sequence: sequence,
+                    confirmable: confirmable,
+                }
+            })
+            .collect()
+    }
+
+    fn remove_observers<F: Fn(&Observer) -> bool>(&mut self, matches: F) {
+        for resource in self.resources.values_mut() {
+            resource.observers.retain(|o| !matches(o));
+        }
+        self.resources.retain(|_, resource| !resource.observers.is_empty());
+    }
+}
+
+/// Joins the segments of a resource path, ignoring empty ones.
+pub fn normalize_path(path: &str) -> String {
+    path.split('/').filter(|s| !s.is_empty()).collect::<Vec<_>>().join("/")
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn peer(port: u16) -> SocketAddr {
+        format!("127.0.0.1:{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn test_notify() {
+        let mut registry = ObserverRegistry::new();
+        assert_eq!(registry.register("/temp", peer(1), vec![1]), 0);
+        assert_eq!(registry.register("temp/", peer(2), vec![2]), 0);
+        assert!(registry.notify("/humidity", || 0).is_empty());
+
+        let mut message_id = 0;
+        let notifications = registry.notify("temp", || {
+            message_id += 1;
+            message_id
+        });
+        assert_eq!(notifications.len(), 2);
+        assert_eq!(notifications[0].address, peer(1));
+        assert_eq!(notifications[0].token, vec![1]);
+        assert_eq!(notifications[0].sequence, 1);
+        assert_eq!(notifications[1].message_id, 2);
+        assert!(!notifications[1].confirmable);
+    }
+
+    #[test]
+    fn test_register_again() {
+        let mut registry = ObserverRegistry::new();
+        registry.register("temp", peer(1), vec![1]);
+        for _ in 0..3 {
+            registry.notify("temp", || 0);
+        }
+        assert_eq!(registry.register("temp", peer(1), vec![1]), 3);
+        let notifications = registry.notify("temp", || 0);
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].sequence, 4);
+
+        // Observing another resource with the same token ends the first observation
+        assert_eq!(registry.register("humidity", peer(1), vec![1]), 0);
+        assert!(registry.notify("temp", || 0).is_empty());
+    }
+
+    #[test]
+    fn test_periodic_confirmable() {
+        let mut registry = ObserverRegistry::new();
+        registry.register("temp", peer(1), vec![1]);
+        let confirmables: Vec<bool> = (0..2 * CONFIRMABLE_INTERVAL)
+            .map(|_| {
+                let notification = registry.notify("temp", || 5).remove(0);
+                registry.acknowledge(peer(1), notification.message_id);
+                notification.confirmable
+            })
+            .collect();
+        assert_eq!(confirmables.iter().filter(|&&c| c).count(), 2);
+        assert!(confirmables[CONFIRMABLE_INTERVAL as usize - 1]);
+    }
+
+    #[test]
+    fn test_unacknowledged_confirmable() {
+        let mut registry = ObserverRegistry::new();
+        registry.register("temp", peer(1), vec![1]);
+        let mut message_id = 0;
+        let mut next_message_id = || {
+            message_id += 1;
+            message_id
+        };
+        for _ in 0..CONFIRMABLE_INTERVAL {
+            registry.notify("temp", &mut next_message_id);
+        }
+
+        // Notifications stay Confirmable until the first one is acknowledged
+        let confirmable_id = CONFIRMABLE_INTERVAL as u16;
+        assert!(registry.notify("temp", &mut next_message_id)[0].confirmable);
+        assert!(registry.notify("temp", &mut next_message_id)[0].confirmable);
+        registry.acknowledge(peer(1), confirmable_id + 1);
+        registry.acknowledge(peer(1), confirmable_id + 2);
+        assert!(registry.notify("temp", &mut next_message_id)[0].confirmable);
+
+        // The first one times out after later notifications were sent
+        registry.remove_by_message(peer(1), confirmable_id);
+        assert!(registry.resources.is_empty());
+    }
+
+    #[test]
+    fn test_reset_older_notification() {
+        let mut registry = ObserverRegistry::new();
+        registry.register("temp", peer(1), vec![1]);
+        registry.notify("temp", || 1);
+        registry.notify("temp", || 2);
+        registry.remove_by_message(peer(1), 3);
+        assert_eq!(registry.notify("temp", || 3).len(), 1);
+        registry.remove_by_message(peer(1), 1);
+        assert!(registry.resources.is_empty());
+    }
+
+    #[test]
+    fn test_sequence_wraps() {
+        let mut registry = ObserverRegistry::new();
+        registry.register("temp", peer(1), vec![1]);
+        registry.resources.get_mut("temp").unwrap().sequence = 0xFFFFFF;
+        assert_eq!(registry.notify("temp", || 0)[0].sequence, 0);
+    }
+
+    #[test]
+    fn test_deregister() {
+        let mut registry = ObserverRegistry::new();
+        registry.register("temp", peer(1), vec![1]);
+        registry.register("temp", peer(1), vec![1]);
+        registry.register("temp", peer(2), vec![2]);
+        assert_eq!(registry.notify("temp", || 7).len(), 2);
+
+        registry.deregister(peer(2), &[2]);
+        assert_eq!(registry.notify("temp", || 8).len(), 1);
+
+        registry.remove_by_message(peer(2), 8);
+        assert_eq!(registry.notify("temp", || 9).len(), 1);
+        registry.remove_by_message(peer(1), 9);
+        assert!(registry.notify("temp", || 10).is_empty());
+        assert!(registry.resources.is_empty());
+    }
+}
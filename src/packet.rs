@@ -1,12 +1,13 @@
-use bincode;
-use std::collections::BTreeMap;
-use std::collections::LinkedList;
-
-macro_rules! u8_to_unsigned_be {
-	($src:ident, $start:expr, $end:expr, $t:ty) => ({
-		(0 .. $end - $start + 1).rev().fold(0, |acc, i| acc | $src[$start+i] as $t << i * 8)
-	})
-}
+use alloc::collections::BTreeMap;
+use alloc::collections::LinkedList;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use alloc::fmt;
+// The 2015 edition only resolves the crate injected by `no_std` or `std`
+#[cfg(not(feature = "std"))]
+use core::net::Ipv4Addr;
+#[cfg(feature = "std")]
+use std::net::Ipv4Addr;
 
 #[derive(PartialEq, Eq, Debug)]
 pub enum PacketType {
@@ -17,37 +18,44 @@ pub enum PacketType {
     Invalid,
 }
 
-#[derive(Default, Debug, RustcEncodable, RustcDecodable)]
+#[derive(Default, Debug)]
 pub struct PacketHeaderRaw {
     ver_type_tkl: u8,
     code: u8,
     message_id: u16,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct PacketHeader {
     ver_type_tkl: u8,
     pub code: PacketClass,
     message_id: u16,
 }
 
-#[derive(Debug, PartialEq)]
+/// The code of a message ([RFC 7252 section 12.1][spec]).
+///
+/// [spec]: https://tools.ietf.org/html/rfc7252#section-12.1
+#[derive(Debug, PartialEq, Clone)]
 pub enum PacketClass {
     Empty,
     Request(Requests),
     Response(Responses),
-    Reserved,
+    /// A code that is not registered, kept as its class and detail.
+    Unknown(u8, u8),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Requests {
     Get,
     Post,
     Put,
     Delete,
+    Fetch,
+    Patch,
+    IPatch,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Responses {
     // 200 Codes
     Created,
@@ -55,6 +63,7 @@ pub enum Responses {
     Valid,
     Changed,
     Content,
+    Continue,
 
     // 400 Codes
     BadRequest,
@@ -64,9 +73,13 @@ pub enum Responses {
     NotFound,
     MethodNotAllowed,
     NotAcceptable,
+    RequestEntityIncomplete,
+    Conflict,
     PreconditionFailed,
     RequestEntityTooLarge,
     UnsupportedContentFormat,
+    UnprocessableEntity,
+    TooManyRequests,
 
     // 500 Codes
     InternalServerError,
@@ -75,6 +88,7 @@ pub enum Responses {
     ServiceUnavailable,
     GatewayTimeout,
     ProxyingNotSupported,
+    HopLimitReached,
 }
 
 pub fn class_to_code(class: &PacketClass) -> u8 {
@@ -85,12 +99,16 @@ pub fn class_to_code(class: &PacketClass) -> u8 {
         PacketClass::Request(Requests::Post) => 0x02,
         PacketClass::Request(Requests::Put) => 0x03,
         PacketClass::Request(Requests::Delete) => 0x04,
+        PacketClass::Request(Requests::Fetch) => 0x05,
+        PacketClass::Request(Requests::Patch) => 0x06,
+        PacketClass::Request(Requests::IPatch) => 0x07,
 
         PacketClass::Response(Responses::Created) => 0x41,
         PacketClass::Response(Responses::Deleted) => 0x42,
         PacketClass::Response(Responses::Valid) => 0x43,
         PacketClass::Response(Responses::Changed) => 0x44,
         PacketClass::Response(Responses::Content) => 0x45,
+        PacketClass::Response(Responses::Continue) => 0x5F,
 
         PacketClass::Response(Responses::BadRequest) => 0x80,
         PacketClass::Response(Responses::Unauthorized) => 0x81,
@@ -99,18 +117,23 @@ pub fn class_to_code(class: &PacketClass) -> u8 {
         PacketClass::Response(Responses::NotFound) => 0x84,
         PacketClass::Response(Responses::MethodNotAllowed) => 0x85,
         PacketClass::Response(Responses::NotAcceptable) => 0x86,
+        PacketClass::Response(Responses::RequestEntityIncomplete) => 0x88,
+        PacketClass::Response(Responses::Conflict) => 0x89,
         PacketClass::Response(Responses::PreconditionFailed) => 0x8C,
         PacketClass::Response(Responses::RequestEntityTooLarge) => 0x8D,
         PacketClass::Response(Responses::UnsupportedContentFormat) => 0x8F,
-
-        PacketClass::Response(Responses::InternalServerError) => 0x90,
-        PacketClass::Response(Responses::NotImplemented) => 0x91,
-        PacketClass::Response(Responses::BadGateway) => 0x92,
-        PacketClass::Response(Responses::ServiceUnavailable) => 0x93,
-        PacketClass::Response(Responses::GatewayTimeout) => 0x94,
-        PacketClass::Response(Responses::ProxyingNotSupported) => 0x95,
-
-        _ => 0xFF,
+        PacketClass::Response(Responses::UnprocessableEntity) => 0x96,
+        PacketClass::Response(Responses::TooManyRequests) => 0x9D,
+
+        PacketClass::Response(Responses::InternalServerError) => 0xA0,
+        PacketClass::Response(Responses::NotImplemented) => 0xA1,
+        PacketClass::Response(Responses::BadGateway) => 0xA2,
+        PacketClass::Response(Responses::ServiceUnavailable) => 0xA3,
+        PacketClass::Response(Responses::GatewayTimeout) => 0xA4,
+        PacketClass::Response(Responses::ProxyingNotSupported) => 0xA5,
+        PacketClass::Response(Responses::HopLimitReached) => 0xA8,
+
+        PacketClass::Unknown(class, detail) => (class << 5) | (detail & 0x1F),
     } as u8;
 }
 
@@ -122,12 +145,16 @@ pub fn code_to_class(code: &u8) -> PacketClass {
         0x02 => PacketClass::Request(Requests::Post),
         0x03 => PacketClass::Request(Requests::Put),
         0x04 => PacketClass::Request(Requests::Delete),
+        0x05 => PacketClass::Request(Requests::Fetch),
+        0x06 => PacketClass::Request(Requests::Patch),
+        0x07 => PacketClass::Request(Requests::IPatch),
 
         0x41 => PacketClass::Response(Responses::Created),
         0x42 => PacketClass::Response(Responses::Deleted),
         0x43 => PacketClass::Response(Responses::Valid),
         0x44 => PacketClass::Response(Responses::Changed),
         0x45 => PacketClass::Response(Responses::Content),
+        0x5F => PacketClass::Response(Responses::Continue),
 
         0x80 => PacketClass::Response(Responses::BadRequest),
         0x81 => PacketClass::Response(Responses::Unauthorized),
@@ -136,18 +163,23 @@ pub fn code_to_class(code: &u8) -> PacketClass {
         0x84 => PacketClass::Response(Responses::NotFound),
         0x85 => PacketClass::Response(Responses::MethodNotAllowed),
         0x86 => PacketClass::Response(Responses::NotAcceptable),
+        0x88 => PacketClass::Response(Responses::RequestEntityIncomplete),
+        0x89 => PacketClass::Response(Responses::Conflict),
         0x8C => PacketClass::Response(Responses::PreconditionFailed),
         0x8D => PacketClass::Response(Responses::RequestEntityTooLarge),
         0x8F => PacketClass::Response(Responses::UnsupportedContentFormat),
-
-        0x90 => PacketClass::Response(Responses::InternalServerError),
-        0x91 => PacketClass::Response(Responses::NotImplemented),
-        0x92 => PacketClass::Response(Responses::BadGateway),
-        0x93 => PacketClass::Response(Responses::ServiceUnavailable),
-        0x94 => PacketClass::Response(Responses::GatewayTimeout),
-        0x95 => PacketClass::Response(Responses::ProxyingNotSupported),
-
-        _ => PacketClass::Reserved,
+        0x96 => PacketClass::Response(Responses::UnprocessableEntity),
+        0x9D => PacketClass::Response(Responses::TooManyRequests),
+
+        0xA0 => PacketClass::Response(Responses::InternalServerError),
+        0xA1 => PacketClass::Response(Responses::NotImplemented),
+        0xA2 => PacketClass::Response(Responses::BadGateway),
+        0xA3 => PacketClass::Response(Responses::ServiceUnavailable),
+        0xA4 => PacketClass::Response(Responses::GatewayTimeout),
+        0xA5 => PacketClass::Response(Responses::ProxyingNotSupported),
+        0xA8 => PacketClass::Response(Responses::HopLimitReached),
+
+        _ => PacketClass::Unknown(code >> 5, code & 0x1F),
     }
 }
 
@@ -194,6 +226,7 @@ impl PacketHeader {
         return self.ver_type_tkl >> 6;
     }
 
+    /// Panics on `PacketType::Invalid`; `PacketBuilder` reports it instead.
     #[inline]
     pub fn set_type(&mut self, t: PacketType) {
         let tn = match t {
@@ -233,6 +266,8 @@ impl PacketHeader {
         return 0x0F & self.ver_type_tkl;
     }
 
+    /// Panics if the code is not of the form "c.dd"; `PacketBuilder` reports
+    ///   it instead.
     pub fn set_code(&mut self, code: &str) {
         let code_vec: Vec<&str> = code.split('.').collect();
         assert_eq!(code_vec.len(), 2);
@@ -272,9 +307,32 @@ pub enum ParseError {
 pub enum PackageError {
     InvalidHeader,
     InvalidPacketLength,
+    BufferTooSmall,
+    InvalidOptionValue,
 }
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Debug, PartialEq)]
+pub enum UriError {
+    NotAbsolute,
+    UnsupportedScheme,
+    HasFragment,
+    InvalidHost,
+    InvalidPort,
+    InvalidPercentEncoding,
+}
+
+/// The largest message sent or received unless configured otherwise, the
+///   smallest IPv6 MTU.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1280;
+/// The largest option delta or length that can be encoded.
+const MAX_EXTENDED: usize = 0xFFFF + 269;
+/// The largest block number that fits in a Block1 or Block2 option.
+const MAX_BLOCK_NUM: u32 = 0xFFFFF;
+
+const DEFAULT_PORT: u16 = 5683;
+const DEFAULT_SECURE_PORT: u16 = 5684;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum OptionType {
     IfMatch,
     UriHost,
@@ -291,12 +349,431 @@ pub enum OptionType {
     LocationQuery,
     Block2,
     Block1,
+    Size2,
     ProxyUri,
     ProxyScheme,
     Size1,
+    RequestTag,
 }
 
-#[derive(Debug)]
+/// The format of an option value ([RFC 7252 section 3.2][spec]).
+///
+/// [spec]: https://tools.ietf.org/html/rfc7252#section-3.2
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OptionFormat {
+    Empty,
+    Opaque,
+    UInt,
+    String,
+}
+
+/// A decoded option value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OptionValue {
+    Empty,
+    Opaque(Vec<u8>),
+    UInt(u32),
+    String(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum OptionError {
+    InvalidFormat,
+    InvalidLength,
+    AlreadyRegistered,
+}
+
+impl OptionType {
+    /// Returns the option number ([RFC 7252 section 12.2][spec]).
+    ///
+    /// [spec]: https://tools.ietf.org/html/rfc7252#section-12.2
+    pub fn number(&self) -> usize {
+        match *self {
+            OptionType::IfMatch => 1,
+            OptionType::UriHost => 3,
+            OptionType::ETag => 4,
+            OptionType::IfNoneMatch => 5,
+            OptionType::Observe => 6,
+            OptionType::UriPort => 7,
+            OptionType::LocationPath => 8,
+            OptionType::UriPath => 11,
+            OptionType::ContentFormat => 12,
+            OptionType::MaxAge => 14,
+            OptionType::UriQuery => 15,
+            OptionType::Accept => 17,
+            OptionType::LocationQuery => 20,
+            OptionType::Block2 => 23,
+            OptionType::Block1 => 27,
+            OptionType::Size2 => 28,
+            OptionType::ProxyUri => 35,
+            OptionType::ProxyScheme => 39,
+            OptionType::Size1 => 60,
+            OptionType::RequestTag => 292,
+        }
+    }
+
+    /// Returns the option with the number, if it is one of the known options.
+    pub fn from_number(number: usize) -> Option<OptionType> {
+        KNOWN_OPTIONS.iter().find(|tp| tp.number() == number).cloned()
+    }
+
+    /// Returns the name of the option as spelled in its specification.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            OptionType::IfMatch => "If-Match",
+            OptionType::UriHost => "Uri-Host",
+            OptionType::ETag => "ETag",
+            OptionType::IfNoneMatch => "If-None-Match",
+            OptionType::Observe => "Observe",
+            OptionType::UriPort => "Uri-Port",
+            OptionType::LocationPath => "Location-Path",
+            OptionType::UriPath => "Uri-Path",
+            OptionType::ContentFormat => "Content-Format",
+            OptionType::MaxAge => "Max-Age",
+            OptionType::UriQuery => "Uri-Query",
+            OptionType::Accept => "Accept",
+            OptionType::LocationQuery => "Location-Query",
+            OptionType::Block2 => "Block2",
+            OptionType::Block1 => "Block1",
+            OptionType::Size2 => "Size2",
+            OptionType::ProxyUri => "Proxy-Uri",
+            OptionType::ProxyScheme => "Proxy-Scheme",
+            OptionType::Size1 => "Size1",
+            OptionType::RequestTag => "Request-Tag",
+        }
+    }
+
+    /// Returns the definition of the option.
+    pub fn definition(&self) -> OptionDefinition {
+        let (min_length, max_length) = self.length_range();
+        let repeatable = match *self {
+            OptionType::IfMatch | OptionType::ETag | OptionType::LocationPath |
+            OptionType::UriPath | OptionType::UriQuery | OptionType::LocationQuery |
+            OptionType::RequestTag => true,
+            _ => false,
+        };
+        OptionDefinition::new(self.number(),
+                              self.name(),
+                              self.format(),
+                              repeatable,
+                              min_length,
+                              max_length)
+    }
+
+    /// Returns the format of the option values.
+    pub fn format(&self) -> OptionFormat {
+        match *self {
+            OptionType::IfNoneMatch => OptionFormat::Empty,
+            OptionType::IfMatch | OptionType::ETag | OptionType::RequestTag => {
+                OptionFormat::Opaque
+            }
+            OptionType::Observe | OptionType::UriPort | OptionType::ContentFormat |
+            OptionType::MaxAge | OptionType::Accept | OptionType::Block2 |
+            OptionType::Block1 | OptionType::Size2 | OptionType::Size1 => OptionFormat::UInt,
+            OptionType::UriHost | OptionType::LocationPath | OptionType::UriPath |
+            OptionType::UriQuery | OptionType::LocationQuery | OptionType::ProxyUri |
+            OptionType::ProxyScheme => OptionFormat::String,
+        }
+    }
+
+    /// Returns the minimum and maximum length of the option values in bytes
+    ///   ([RFC 7252 section 5.10][spec]).
+    ///
+    /// [spec]: https://tools.ietf.org/html/rfc7252#section-5.10
+    pub fn length_range(&self) -> (usize, usize) {
+        match *self {
+            OptionType::IfMatch => (0, 8),
+            OptionType::UriHost => (1, 255),
+            OptionType::ETag => (1, 8),
+            OptionType::IfNoneMatch => (0, 0),
+            OptionType::Observe => (0, 3),
+            OptionType::UriPort => (0, 2),
+            OptionType::LocationPath => (0, 255),
+            OptionType::UriPath => (0, 255),
+            OptionType::ContentFormat => (0, 2),
+            OptionType::MaxAge => (0, 4),
+            OptionType::UriQuery => (0, 255),
+            OptionType::Accept => (0, 2),
+            OptionType::LocationQuery => (0, 255),
+            OptionType::Block2 => (0, 3),
+            OptionType::Block1 => (0, 3),
+            OptionType::Size2 => (0, 4),
+            OptionType::ProxyUri => (1, 1034),
+            OptionType::ProxyScheme => (1, 255),
+            OptionType::Size1 => (0, 4),
+            OptionType::RequestTag => (0, 8),
+        }
+    }
+
+    /// Checks the encoded value against the length limits of the option.
+    fn check_length(&self, value: &[u8]) -> Result<(), OptionError> {
+        let (min, max) = self.length_range();
+        if value.len() < min || value.len() > max {
+            return Err(OptionError::InvalidLength);
+        }
+        Ok(())
+    }
+}
+
+impl OptionValue {
+    /// Decodes an option value in the format, or returns `None` if the value
+    ///   is not valid in that format.
+    pub fn from_bytes(format: OptionFormat, value: &[u8]) -> Option<OptionValue> {
+        match format {
+            OptionFormat::Empty if value.is_empty() => Some(OptionValue::Empty),
+            OptionFormat::Empty => None,
+            OptionFormat::Opaque => Some(OptionValue::Opaque(value.to_vec())),
+            OptionFormat::UInt if value.len() <= 4 => {
+                Some(OptionValue::UInt(value.iter().fold(0, |acc, &b| acc << 8 | b as u32)))
+            }
+            OptionFormat::UInt => None,
+            OptionFormat::String => String::from_utf8(value.to_vec()).ok().map(OptionValue::String),
+        }
+    }
+
+    /// Encodes the value, a uint in the fewest bytes possible.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match *self {
+            OptionValue::Empty => Vec::new(),
+            OptionValue::Opaque(ref value) => value.clone(),
+            OptionValue::UInt(value) => {
+                let bytes = [(value >> 24) as u8,
+                             (value >> 16) as u8,
+                             (value >> 8) as u8,
+                             value as u8];
+                match bytes.iter().position(|&b| b != 0) {
+                    Some(start) => bytes[start..].to_vec(),
+                    None => Vec::new(),
+                }
+            }
+            OptionValue::String(ref value) => value.clone().into_bytes(),
+        }
+    }
+
+    fn format(&self) -> OptionFormat {
+        match *self {
+            OptionValue::Empty => OptionFormat::Empty,
+            OptionValue::Opaque(_) => OptionFormat::Opaque,
+            OptionValue::UInt(_) => OptionFormat::UInt,
+            OptionValue::String(_) => OptionFormat::String,
+        }
+    }
+}
+
+const KNOWN_OPTIONS: [OptionType; 20] = [OptionType::IfMatch,
+                                         OptionType::UriHost,
+                                         OptionType::ETag,
+                                         OptionType::IfNoneMatch,
+                                         OptionType::Observe,
+                                         OptionType::UriPort,
+                                         OptionType::LocationPath,
+                                         OptionType::UriPath,
+                                         OptionType::ContentFormat,
+                                         OptionType::MaxAge,
+                                         OptionType::UriQuery,
+                                         OptionType::Accept,
+                                         OptionType::LocationQuery,
+                                         OptionType::Block2,
+                                         OptionType::Block1,
+                                         OptionType::Size2,
+                                         OptionType::ProxyUri,
+                                         OptionType::ProxyScheme,
+                                         OptionType::Size1,
+                                         OptionType::RequestTag];
+
+/// Whether the recipient must understand the option to process the message
+///   ([RFC 7252 section 5.4.6][spec]).
+///
+/// [spec]: https://tools.ietf.org/html/rfc7252#section-5.4.6
+pub fn is_critical_option(number: usize) -> bool {
+    number & 0x01 != 0
+}
+
+/// Whether a proxy that does not understand the option must not forward the
+///   message.
+pub fn is_unsafe_option(number: usize) -> bool {
+    number & 0x02 != 0
+}
+
+/// Whether the option is left out of the cache key. Only meaningful for
+///   options that are safe to forward.
+pub fn is_no_cache_key_option(number: usize) -> bool {
+    number & 0x1E == 0x1C
+}
+
+/// The definition of an option: its number, name, value format and length
+///   limits, and whether it may occur more than once in a message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptionDefinition {
+    pub number: usize,
+    pub name: String,
+    pub format: OptionFormat,
+    pub repeatable: bool,
+    pub min_length: usize,
+    pub max_length: usize,
+}
+
+impl OptionDefinition {
+    pub fn new(number: usize,
+               name: &str,
+               format: OptionFormat,
+               repeatable: bool,
+               min_length: usize,
+               max_length: usize)
+               -> OptionDefinition {
+        OptionDefinition {
+            number: number,
+            name: name.to_string(),
+            format: format,
+            repeatable: repeatable,
+            min_length: min_length,
+            max_length: max_length,
+        }
+    }
+
+    /// Encodes a value of the option, checking its format and length.
+    pub fn encode(&self, value: &OptionValue) -> Result<Vec<u8>, OptionError> {
+        if value.format() != self.format {
+            return Err(OptionError::InvalidFormat);
+        }
+        let bytes = value.to_bytes();
+        if bytes.len() < self.min_length || bytes.len() > self.max_length {
+            return Err(OptionError::InvalidLength);
+        }
+        Ok(bytes)
+    }
+
+    /// Decodes a value of the option, or returns `None` if it is invalid.
+    pub fn decode(&self, value: &[u8]) -> Option<OptionValue> {
+        if value.len() < self.min_length || value.len() > self.max_length {
+            return None;
+        }
+        OptionValue::from_bytes(self.format, value)
+    }
+}
+
+/// The options known to an application: the ones defined by RFC 7252, 7641,
+///   7959 and 9175 (Request-Tag), and the ones it registered itself.
+#[derive(Debug, Clone)]
+pub struct OptionRegistry {
+    definitions: BTreeMap<usize, OptionDefinition>,
+}
+
+impl OptionRegistry {
+    pub fn new() -> OptionRegistry {
+        let mut definitions = BTreeMap::new();
+        for tp in KNOWN_OPTIONS.iter() {
+            definitions.insert(tp.number(), tp.definition());
+        }
+        OptionRegistry { definitions: definitions }
+    }
+
+    /// Registers the definition of an application option. Fails if an option
+    ///   with the same number or name is already known.
+    pub fn register(&mut self, definition: OptionDefinition) -> Result<(), OptionError> {
+        if self.definitions.contains_key(&definition.number) ||
+           self.get_by_name(&definition.name).is_some() {
+            return Err(OptionError::AlreadyRegistered);
+        }
+        self.definitions.insert(definition.number, definition);
+        Ok(())
+    }
+
+    pub fn get(&self, number: usize) -> Option<&OptionDefinition> {
+        self.definitions.get(&number)
+    }
+
+    pub fn get_by_name(&self, name: &str) -> Option<&OptionDefinition> {
+        self.definitions.values().find(|definition| definition.name == name)
+    }
+
+    pub fn is_known(&self, number: usize) -> bool {
+        self.definitions.contains_key(&number)
+    }
+}
+
+/// The value of a Block1 or Block2 option ([RFC 7959 section 2.2][spec]).
+///
+/// [spec]: https://tools.ietf.org/html/rfc7959#section-2.2
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockValue {
+    /// The relative number of the block within the sequence of blocks.
+    pub num: u32,
+    /// Whether more blocks follow this one.
+    pub more: bool,
+    /// The block size is 2 ** (size_exponent + 4) bytes, from 16 to 1024.
+    pub size_exponent: u8,
+}
+
+impl BlockValue {
+    /// Returns `None` if the block number does not fit in the option or the
+    ///   size exponent is larger than 6.
+    pub fn new(num: u32, more: bool, size_exponent: u8) -> Option<BlockValue> {
+        let block = BlockValue {
+            num: num,
+            more: more,
+            size_exponent: size_exponent,
+        };
+        if block.is_valid() {
+            Some(block)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the size exponent of a block size in bytes, which must be a
+    ///   power of two from 16 to 1024.
+    pub fn size_exponent_of(size: usize) -> Option<u8> {
+        (0..7).find(|&e| BlockValue::size_of(e) == size)
+    }
+
+    /// Returns the block size in bytes of a size exponent.
+    pub fn size_of(size_exponent: u8) -> usize {
+        1 << (size_exponent + 4)
+    }
+
+    /// The block size in bytes.
+    pub fn size(&self) -> usize {
+        BlockValue::size_of(self.size_exponent)
+    }
+
+    /// The offset of the block in the whole body.
+    pub fn offset(&self) -> usize {
+        self.num as usize * self.size()
+    }
+
+    pub fn from_bytes(value: &[u8]) -> Option<BlockValue> {
+        if value.len() > 3 {
+            return None;
+        }
+        let raw = value.iter().fold(0u32, |acc, &b| acc << 8 | b as u32);
+        let size_exponent = (raw & 0x7) as u8;
+        if size_exponent == 7 {
+            return None;
+        }
+        BlockValue::new(raw >> 4, raw & 0x8 != 0, size_exponent)
+    }
+
+    /// Encodes the option value, failing if the block number or the size
+    ///   exponent is out of range.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, PackageError> {
+        if !self.is_valid() {
+            return Err(PackageError::InvalidOptionValue);
+        }
+        let raw = self.num << 4 | (self.more as u32) << 3 | self.size_exponent as u32;
+        let bytes = [(raw >> 16) as u8, (raw >> 8) as u8, raw as u8];
+        Ok(match bytes.iter().position(|&b| b != 0) {
+            Some(start) => bytes[start..].to_vec(),
+            None => Vec::new(),
+        })
+    }
+
+    fn is_valid(&self) -> bool {
+        self.num <= MAX_BLOCK_NUM && self.size_exponent <= 6
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Packet {
     pub header: PacketHeader,
     token: Vec<u8>,
@@ -314,6 +791,59 @@ impl Packet {
         }
     }
 
+    /// Starts a response to the request with the code, no options and no
+    ///   payload: an ACK to a Confirmable request, a Non-confirmable response
+    ///   to a Non-confirmable one. Returns `None` for messages that are not
+    ///   answered with a response, i.e. empty messages, ACKs, RSTs and
+    ///   responses.
+    pub fn response_to(request: &Packet, code: Responses) -> Option<Packet> {
+        let is_request = request.is_request();
+        let response_type = match request.header.get_type() {
+            PacketType::Confirmable if is_request => PacketType::Acknowledgement,
+            PacketType::NonConfirmable if is_request => PacketType::NonConfirmable,
+            _ => return None,
+        };
+
+        let mut packet = Packet::new();
+        packet.header.set_version(1);
+        packet.header.set_type(response_type);
+        packet.header.code = PacketClass::Response(code);
+        packet.header.set_message_id(request.header.get_message_id());
+        packet.set_token(request.get_token().clone());
+        Some(packet)
+    }
+
+    /// Starts a response to the request with the usual success code of its
+    ///   method: 2.05 Content for GET and FETCH, 2.04 Changed for POST, PUT,
+    ///   PATCH and iPATCH, and 2.02 Deleted for DELETE. Unknown methods get
+    ///   4.05 Method Not Allowed. A POST that creates a resource should
+    ///   answer 2.01 Created instead.
+    pub fn default_response_to(request: &Packet) -> Option<Packet> {
+        let code = match request.header.code {
+            PacketClass::Request(Requests::Get) |
+            PacketClass::Request(Requests::Fetch) => Responses::Content,
+            PacketClass::Request(Requests::Post) |
+            PacketClass::Request(Requests::Put) |
+            PacketClass::Request(Requests::Patch) |
+            PacketClass::Request(Requests::IPatch) => Responses::Changed,
+            PacketClass::Request(Requests::Delete) => Responses::Deleted,
+            _ => Responses::MethodNotAllowed,
+        };
+        Packet::response_to(request, code)
+    }
+
+    /// Whether the code is a method code, including methods this library
+    ///   does not know.
+    pub fn is_request(&self) -> bool {
+        match self.header.code {
+            PacketClass::Request(_) => true,
+            PacketClass::Unknown(0, _) => true,
+            _ => false,
+        }
+    }
+
+    /// Panics if the token is longer than 15 bytes; `PacketBuilder` also
+    ///   rejects tokens longer than 8 bytes.
     pub fn set_token(&mut self, token: Vec<u8>) {
         self.header.set_token_length(token.len() as u8);
         self.token = token;
@@ -324,8 +854,7 @@ impl Packet {
     }
 
     pub fn set_option(&mut self, tp: OptionType, value: LinkedList<Vec<u8>>) {
-        let num = Self::get_option_number(tp);
-        self.options.insert(num, value);
+        self.set_raw_option(tp.number(), value);
     }
 
     pub fn set_payload(&mut self, payload: Vec<u8>) {
@@ -333,8 +862,25 @@ impl Packet {
     }
 
     pub fn add_option(&mut self, tp: OptionType, value: Vec<u8>) {
-        let num = Self::get_option_number(tp);
-        match self.options.get_mut(&num) {
+        self.add_raw_option(tp.number(), value);
+    }
+
+    pub fn get_option(&self, tp: OptionType) -> Option<LinkedList<Vec<u8>>> {
+        self.get_raw_option(tp.number())
+    }
+
+    pub fn clear_option(&mut self, tp: OptionType) {
+        self.clear_raw_option(tp.number());
+    }
+
+    /// Sets the values of the option with the number, which may be an option
+    ///   unknown to this library.
+    pub fn set_raw_option(&mut self, number: usize, value: LinkedList<Vec<u8>>) {
+        self.options.insert(number, value);
+    }
+
+    pub fn add_raw_option(&mut self, number: usize, value: Vec<u8>) {
+        match self.options.get_mut(&number) {
             Some(list) => {
                 list.push_back(value);
                 return;
@@ -344,311 +890,980 @@ impl Packet {
 
         let mut list = LinkedList::new();
         list.push_back(value);
-        self.options.insert(num, list);
+        self.options.insert(number, list);
     }
 
-    pub fn get_option(&self, tp: OptionType) -> Option<LinkedList<Vec<u8>>> {
-        let num = Self::get_option_number(tp);
-        match self.options.get(&num) {
+    pub fn get_raw_option(&self, number: usize) -> Option<LinkedList<Vec<u8>>> {
+        match self.options.get(&number) {
             Some(options) => Some(options.clone()),
             None => None,
         }
     }
 
-    /// Decodes a byte slice and construct the equivalent Packet.
-    pub fn from_bytes(buf: &[u8]) -> Result<Packet, ParseError> {
-        let header_result: bincode::DecodingResult<PacketHeaderRaw> = bincode::decode(buf);
-        match header_result {
-            Ok(raw_header) => {
-                let header = PacketHeader::from_raw(&raw_header);
-                let token_length = header.get_token_length();
-                let options_start: usize = 4 + token_length as usize;
-
-                if token_length > 8 {
-                    return Err(ParseError::InvalidTokenLength);
-                }
+    pub fn clear_raw_option(&mut self, number: usize) {
+        self.options.remove(&number);
+    }
 
-                if options_start > buf.len() {
-                    return Err(ParseError::InvalidTokenLength);
-                }
+    /// Returns the numbers of the options in the packet, in ascending order.
+    pub fn get_option_numbers(&self) -> Vec<usize> {
+        self.options.keys().cloned().collect()
+    }
 
-                let token = buf[4..options_start].to_vec();
-
-                let mut idx = options_start;
-                let mut options_number = 0;
-                let mut options: BTreeMap<usize, LinkedList<Vec<u8>>> = BTreeMap::new();
-                while idx < buf.len() {
-                    let byte = buf[idx];
-
-                    if byte == 255 || idx > buf.len() {
-                        break;
-                    }
-
-                    let mut delta = (byte >> 4) as usize;
-                    let mut length = (byte & 0xF) as usize;
-
-                    idx += 1;
-
-                    // Check for special delta characters
-                    match delta {
-                        13 => {
-                            if idx >= buf.len() {
-                                return Err(ParseError::InvalidOptionLength);
-                            }
-                            delta = buf[idx] as usize + 13;
-                            idx += 1;
-                        }
-                        14 => {
-                            if idx + 1 >= buf.len() {
-                                return Err(ParseError::InvalidOptionLength);
-                            }
-
-                            delta = (u16::from_be(u8_to_unsigned_be!(buf, idx, idx + 1, u16)) +
-                                     269) as usize;
-                            idx += 2;
-                        }
-                        15 => {
-                            return Err(ParseError::InvalidOptionDelta);
-                        }
-                        _ => {}
-                    };
-
-                    // Check for special length characters
-                    match length {
-                        13 => {
-                            if idx >= buf.len() {
-                                return Err(ParseError::InvalidOptionLength);
-                            }
-
-                            length = buf[idx] as usize + 13;
-                            idx += 1;
-                        }
-                        14 => {
-                            if idx + 1 >= buf.len() {
-                                return Err(ParseError::InvalidOptionLength);
-                            }
-
-                            length = (u16::from_be(u8_to_unsigned_be!(buf, idx, idx + 1, u16)) +
-                                      269) as usize;
-                            idx += 2;
-                        }
-                        15 => {
-                            return Err(ParseError::InvalidOptionLength);
-                        }
-                        _ => {}
-                    };
-
-                    options_number += delta;
-
-                    let end = idx + length;
-                    if end > buf.len() {
-                        return Err(ParseError::InvalidOptionLength);
-                    }
-                    let options_value = buf[idx..end].to_vec();
-
-                    if options.contains_key(&options_number) {
-                        let mut options_list = options.get_mut(&options_number).unwrap();
-                        options_list.push_back(options_value);
-                    } else {
-                        let mut list = LinkedList::new();
-                        list.push_back(options_value);
-                        options.insert(options_number, list);
-                    }
-
-                    idx += length;
-                }
+    /// Adds a value of an option defined by the application, checking its
+    ///   format and length. A value of an option that is not repeatable
+    ///   replaces the previous one.
+    pub fn add_defined_option(&mut self,
+                              definition: &OptionDefinition,
+                              value: OptionValue)
+                              -> Result<(), OptionError> {
+        let bytes = try!(definition.encode(&value));
+        if !definition.repeatable {
+            self.clear_raw_option(definition.number);
+        }
+        self.add_raw_option(definition.number, bytes);
+        Ok(())
+    }
 
-                let mut payload = Vec::new();
-                if idx < buf.len() {
-                    payload = buf[(idx + 1)..buf.len()].to_vec();
-                }
+    /// Returns the values of an option defined by the application, leaving
+    ///   out the invalid ones.
+    pub fn get_defined_option(&self, definition: &OptionDefinition) -> Vec<OptionValue> {
+        match self.options.get(&definition.number) {
+            Some(values) => values.iter().filter_map(|value| definition.decode(value)).collect(),
+            None => Vec::new(),
+        }
+    }
 
+    /// Adds a value of the option, checking its format and length.
+    pub fn add_option_value(&mut self,
+                            tp: OptionType,
+                            value: OptionValue)
+                            -> Result<(), OptionError> {
+        let bytes = try!(Self::encode_option_value(&tp, &value));
+        self.add_option(tp, bytes);
+        Ok(())
+    }
 
-                Ok(Packet {
-                    header: header,
-                    token: token,
-                    options: options,
-                    payload: payload,
-                })
-            }
-            Err(_) => Err(ParseError::InvalidHeader),
-        }
+    /// Replaces the values of the option with a single value, checking its
+    ///   format and length.
+    pub fn set_option_value(&mut self,
+                            tp: OptionType,
+                            value: OptionValue)
+                            -> Result<(), OptionError> {
+        let bytes = try!(Self::encode_option_value(&tp, &value));
+        let mut list = LinkedList::new();
+        list.push_back(bytes);
+        self.set_option(tp, list);
+        Ok(())
     }
 
-    /// Returns a vector of bytes representing the Packet.
-    pub fn to_bytes(&self) -> Result<Vec<u8>, PackageError> {
-        let mut options_delta_length = 0;
-        let mut options_bytes: Vec<u8> = Vec::new();
-        for (number, value_list) in self.options.iter() {
-            for value in value_list.iter() {
-                let mut header: Vec<u8> = Vec::with_capacity(1 + 2 + 2);
-                let delta = number - options_delta_length;
-
-                let mut byte: u8 = 0;
-                if delta <= 12 {
-                    byte |= (delta << 4) as u8;
-                } else if delta < 269 {
-                    byte |= 13 << 4;
-                } else {
-                    byte |= 14 << 4;
-                }
-                if value.len() <= 12 {
-                    byte |= value.len() as u8;
-                } else if value.len() < 269 {
-                    byte |= 13;
-                } else {
-                    byte |= 14;
-                }
-                header.push(byte);
-
-                if delta > 12 && delta < 269 {
-                    header.push((delta - 13) as u8);
-                } else if delta >= 269 {
-                    let fix = (delta - 269) as u16;
-                    header.push((fix >> 8) as u8);
-                    header.push((fix & 0xFF) as u8);
-                }
+    /// Returns the first value of the option, if it is valid.
+    pub fn get_option_value(&self, tp: OptionType) -> Option<OptionValue> {
+        self.get_option_values(tp).into_iter().next()
+    }
 
-                if value.len() > 12 && value.len() < 269 {
-                    header.push((value.len() - 13) as u8);
-                } else if value.len() >= 269 {
-                    let fix = (value.len() - 269) as u16;
-                    header.push((fix >> 8) as u8);
-                    header.push((fix & 0xFF) as u8);
-                }
+    /// Returns the values of the option, leaving out the invalid ones.
+    pub fn get_option_values(&self, tp: OptionType) -> Vec<OptionValue> {
+        let values = match self.options.get(&tp.number()) {
+            Some(values) => values,
+            None => return Vec::new(),
+        };
+        values.iter()
+            .filter(|value| tp.check_length(value).is_ok())
+            .filter_map(|value| OptionValue::from_bytes(tp.format(), value))
+            .collect()
+    }
 
-                options_delta_length += delta;
-
-                options_bytes.reserve(header.len() + value.len());
-                unsafe {
-                    use std::ptr;
-                    let buf_len = options_bytes.len();
-                    ptr::copy(header.as_ptr(),
-                              options_bytes.as_mut_ptr().offset(buf_len as isize),
-                              header.len());
-                    ptr::copy(value.as_ptr(),
-                              options_bytes.as_mut_ptr().offset((buf_len + header.len()) as isize),
-                              value.len());
-                    options_bytes.set_len(buf_len + header.len() + value.len());
-                }
-            }
-        }
+    pub fn get_content_format(&self) -> Option<u16> {
+        self.get_uint(OptionType::ContentFormat).map(|format| format as u16)
+    }
 
-        let mut buf_length = 4 + self.payload.len() + self.token.len();
-        if self.header.code != PacketClass::Empty && self.payload.len() != 0 {
-            buf_length += 1;
-        }
-        buf_length += options_bytes.len();
+    pub fn set_content_format(&mut self, format: u16) {
+        self.set_uint(OptionType::ContentFormat, format as u32);
+    }
 
-        if buf_length > 1280 {
-            return Err(PackageError::InvalidPacketLength);
+    pub fn get_accept(&self) -> Option<u16> {
+        self.get_uint(OptionType::Accept).map(|format| format as u16)
+    }
+
+    pub fn set_accept(&mut self, format: u16) {
+        self.set_uint(OptionType::Accept, format as u32);
+    }
+
+    pub fn get_max_age(&self) -> Option<u32> {
+        self.get_uint(OptionType::MaxAge)
+    }
+
+    pub fn set_max_age(&mut self, seconds: u32) {
+        self.set_uint(OptionType::MaxAge, seconds);
+    }
+
+    pub fn get_observe(&self) -> Option<u32> {
+        self.get_uint(OptionType::Observe)
+    }
+
+    /// Sets the Observe option. Only the lower 24 bits of the value are sent.
+    pub fn set_observe(&mut self, value: u32) {
+        self.set_uint(OptionType::Observe, value & 0xFFFFFF);
+    }
+
+    pub fn get_uri_port(&self) -> Option<u16> {
+        self.get_uint(OptionType::UriPort).map(|port| port as u16)
+    }
+
+    pub fn set_uri_port(&mut self, port: u16) {
+        self.set_uint(OptionType::UriPort, port as u32);
+    }
+
+    pub fn get_uri_host(&self) -> Option<String> {
+        match self.get_option_value(OptionType::UriHost) {
+            Some(OptionValue::String(host)) => Some(host),
+            _ => None,
         }
+    }
 
-        let mut buf: Vec<u8> = Vec::with_capacity(buf_length);
-        let header_result: bincode::EncodingResult<()> =
-            bincode::encode_into(&self.header.to_raw(),
-                                 &mut buf,
-                                 bincode::SizeLimit::Infinite);
-        match header_result {
-            Ok(_) => {
-                buf.reserve(self.token.len() + options_bytes.len());
-                unsafe {
-                    use std::ptr;
-                    let buf_len = buf.len();
-                    ptr::copy(self.token.as_ptr(),
-                              buf.as_mut_ptr().offset(buf_len as isize),
-                              self.token.len());
-                    ptr::copy(options_bytes.as_ptr(),
-                              buf.as_mut_ptr().offset((buf_len + self.token.len()) as isize),
-                              options_bytes.len());
-                    buf.set_len(buf_len + self.token.len() + options_bytes.len());
-                }
+    pub fn set_uri_host(&mut self, host: &str) -> Result<(), OptionError> {
+        self.set_option_value(OptionType::UriHost, OptionValue::String(host.to_string()))
+    }
 
-                if self.header.code != PacketClass::Empty && self.payload.len() != 0 {
-                    buf.push(0xFF);
-                    buf.reserve(self.payload.len());
-                    unsafe {
-                        use std::ptr;
-                        let buf_len = buf.len();
-                        ptr::copy(self.payload.as_ptr(),
-                                  buf.as_mut_ptr().offset(buf.len() as isize),
-                                  self.payload.len());
-                        buf.set_len(buf_len + self.payload.len());
-                    }
-                }
-                Ok(buf)
+    /// Returns the Uri-Path segments joined by slashes, without a leading one.
+    pub fn get_uri_path_string(&self) -> String {
+        match self.options.get(&OptionType::UriPath.number()) {
+            Some(segments) => {
+                segments.iter()
+                    .map(|s| String::from_utf8_lossy(s).into_owned())
+                    .collect::<Vec<_>>()
+                    .join("/")
             }
-            Err(_) => Err(PackageError::InvalidHeader),
+            None => String::new(),
         }
     }
 
-    fn get_option_number(tp: OptionType) -> usize {
-        match tp {
-            OptionType::IfMatch => 1,
-            OptionType::UriHost => 3,
-            OptionType::ETag => 4,
-            OptionType::IfNoneMatch => 5,
-            OptionType::Observe => 6,
-            OptionType::UriPort => 7,
-            OptionType::LocationPath => 8,
-            OptionType::UriPath => 11,
-            OptionType::ContentFormat => 12,
-            OptionType::MaxAge => 14,
-            OptionType::UriQuery => 15,
-            OptionType::Accept => 17,
-            OptionType::LocationQuery => 20,
-            OptionType::Block2 => 23,
-            OptionType::Block1 => 27,
-            OptionType::ProxyUri => 35,
-            OptionType::ProxyScheme => 39,
-            OptionType::Size1 => 60,
+    /// Sets the Uri-Path options from the segments of a path, ignoring a
+    ///   leading slash.
+    pub fn set_uri_path_string(&mut self, path: &str) -> Result<(), OptionError> {
+        let path = if path.starts_with('/') { &path[1..] } else { path };
+        let mut segments = LinkedList::new();
+        if !path.is_empty() {
+            for segment in path.split('/') {
+                let value = OptionValue::String(segment.to_string());
+                segments.push_back(try!(Self::encode_option_value(&OptionType::UriPath, &value)));
+            }
+        }
+        self.clear_option(OptionType::UriPath);
+        if !segments.is_empty() {
+            self.set_option(OptionType::UriPath, segments);
         }
+        Ok(())
     }
-}
 
-/// Convert a request to a response
-pub fn auto_response(request_packet: &Packet) -> Option<Packet> {
-    let mut packet = Packet::new();
+    fn get_uint(&self, tp: OptionType) -> Option<u32> {
+        match self.get_option_value(tp) {
+            Some(OptionValue::UInt(value)) => Some(value),
+            _ => None,
+        }
+    }
 
-    packet.header.set_version(1);
-    let response_type = match request_packet.header.get_type() {
-        PacketType::Confirmable => PacketType::Acknowledgement,
-        PacketType::NonConfirmable => PacketType::NonConfirmable,
-        _ => return None,
-    };
-    packet.header.set_type(response_type);
-    packet.header.code = PacketClass::Response(Responses::Content);
-    packet.header.set_message_id(request_packet.header.get_message_id());
-    packet.set_token(request_packet.get_token().clone());
+    /// Sets a uint option with a value known to fit in its length limits.
+    fn set_uint(&mut self, tp: OptionType, value: u32) {
+        let mut list = LinkedList::new();
+        list.push_back(OptionValue::UInt(value).to_bytes());
+        self.set_option(tp, list);
+    }
 
-    packet.payload = request_packet.payload.clone();
+    fn encode_option_value(tp: &OptionType, value: &OptionValue) -> Result<Vec<u8>, OptionError> {
+        if value.format() != tp.format() {
+            return Err(OptionError::InvalidFormat);
+        }
+        let bytes = value.to_bytes();
+        try!(tp.check_length(&bytes));
+        Ok(bytes)
+    }
 
-    Some(packet)
-}
+    pub fn get_block2(&self) -> Option<BlockValue> {
+        self.get_block(OptionType::Block2)
+    }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use std::collections::LinkedList;
+    pub fn set_block2(&mut self, block: BlockValue) -> Result<(), PackageError> {
+        self.set_block(OptionType::Block2, block)
+    }
 
-    #[test]
-    fn test_header_codes() {
-        for code in 0..255 {
-            let class = code_to_class(&code);
-            let code_str = code_to_str(&code);
-            let class_str = class_to_str(&class);
+    pub fn get_block1(&self) -> Option<BlockValue> {
+        self.get_block(OptionType::Block1)
+    }
 
-            // Reserved class could technically be many codes
-            //   so only check valid items
-            if class != PacketClass::Reserved {
-                assert_eq!(class_to_code(&class), code);
-                assert_eq!(code_str, class_str);
-            }
-        }
+    pub fn set_block1(&mut self, block: BlockValue) -> Result<(), PackageError> {
+        self.set_block(OptionType::Block1, block)
     }
 
-    #[test]
-    fn test_decode_packet_with_options() {
+    pub fn get_size1(&self) -> Option<u32> {
+        self.get_uint(OptionType::Size1)
+    }
+
+    pub fn set_size1(&mut self, size: u32) {
+        self.set_uint(OptionType::Size1, size);
+    }
+
+    pub fn get_size2(&self) -> Option<u32> {
+        self.get_uint(OptionType::Size2)
+    }
+
+    pub fn set_size2(&mut self, size: u32) {
+        self.set_uint(OptionType::Size2, size);
+    }
+
+    /// Sets the Uri-Host, Uri-Path and Uri-Query options from a `coap` or
+    ///   `coaps` URI ([RFC 7252 section 6.4][spec]), replacing the previous
+    ///   ones. Returns the host and port of the endpoint to send the request
+    ///   to, so a Uri-Port option is never needed. Uri-Host is only set if the
+    ///   host is a name rather than an IP address.
+    ///   The "." and ".." segments of the path are resolved.
+    ///
+    /// [spec]: https://tools.ietf.org/html/rfc7252#section-6.4
+    pub fn set_uri(&mut self, uri: &str) -> Result<(String, u16), UriError> {
+        let separator = match uri.find(':') {
+            Some(separator) => separator,
+            None => return Err(UriError::NotAbsolute),
+        };
+        let default_port = match &uri[..separator].to_lowercase()[..] {
+            "coap" => DEFAULT_PORT,
+            "coaps" => DEFAULT_SECURE_PORT,
+            _ => return Err(UriError::UnsupportedScheme),
+        };
+        if !uri[separator + 1..].starts_with("//") {
+            return Err(UriError::NotAbsolute);
+        }
+        if uri.contains('#') {
+            return Err(UriError::HasFragment);
+        }
+
+        let rest = &uri[separator + 3..];
+        let authority_end = rest.find(|c| c == '/' || c == '?').unwrap_or(rest.len());
+        let (authority, rest) = rest.split_at(authority_end);
+        // An empty query, as in a bare trailing "?", has no arguments
+        let (path, query) = match rest.find('?') {
+            Some(start) if start + 1 < rest.len() => (&rest[..start], Some(&rest[start + 1..])),
+            Some(start) => (&rest[..start], None),
+            None => (rest, None),
+        };
+
+        // The host is an IP literal in brackets, an IPv4 address or a name
+        let (host, port, literal) = if authority.starts_with('[') {
+            match authority.find(']') {
+                Some(end) => (&authority[1..end], &authority[end + 1..], true),
+                None => return Err(UriError::InvalidHost),
+            }
+        } else {
+            match authority.find(':') {
+                Some(start) => (&authority[..start], &authority[start..], false),
+                None => (authority, "", false),
+            }
+        };
+        let port = match port {
+            "" | ":" => default_port,
+            _ if port.starts_with(':') => {
+                match port[1..].parse::<u16>() {
+                    Ok(port) if port > 0 => port,
+                    _ => return Err(UriError::InvalidPort),
+                }
+            }
+            _ => return Err(UriError::InvalidHost),
+        };
+        let host = match String::from_utf8(try!(percent_decode(host))) {
+            Ok(ref host) if OptionType::UriHost.check_length(host.as_bytes()).is_ok() => {
+                host.to_lowercase()
+            }
+            _ => return Err(UriError::InvalidHost),
+        };
+
+        let path = remove_dot_segments(path);
+        let mut segments = Vec::new();
+        if path != "" && path != "/" {
+            for segment in path[1..].split('/') {
+                segments.push(try!(percent_decode(segment)));
+            }
+        }
+        let mut arguments = Vec::new();
+        if let Some(query) = query {
+            for argument in query.split('&') {
+                arguments.push(try!(percent_decode(argument)));
+            }
+        }
+
+        self.clear_option(OptionType::UriHost);
+        self.clear_option(OptionType::UriPort);
+        self.clear_option(OptionType::UriPath);
+        self.clear_option(OptionType::UriQuery);
+        if !literal && host.parse::<Ipv4Addr>().is_err() {
+            self.add_option(OptionType::UriHost, host.clone().into_bytes());
+        }
+        for segment in segments {
+            self.add_option(OptionType::UriPath, segment);
+        }
+        for argument in arguments {
+            self.add_option(OptionType::UriQuery, argument);
+        }
+        Ok((host, port))
+    }
+
+    /// Composes the `coap` URI of a request from its Uri-Host, Uri-Port,
+    ///   Uri-Path and Uri-Query options ([RFC 7252 section 6.5][spec]). The
+    ///   host and port of the endpoint the request was sent to are used
+    ///   unless the request has a Uri-Host or Uri-Port option.
+    ///
+    /// [spec]: https://tools.ietf.org/html/rfc7252#section-6.5
+    pub fn get_uri(&self, host: &str, port: u16) -> String {
+        let mut uri = String::from("coap://");
+        match self.get_first_option(OptionType::UriHost) {
+            Some(value) => uri.push_str(&percent_encode(value, is_host_char)),
+            None if host.contains(':') => uri.push_str(&format!("[{}]", host)),
+            None => uri.push_str(host),
+        }
+
+        let port = self.get_uri_port().unwrap_or(port);
+        if port != DEFAULT_PORT {
+            uri.push_str(&format!(":{}", port));
+        }
+
+        match self.options.get(&OptionType::UriPath.number()) {
+            Some(segments) => {
+                for segment in segments.iter() {
+                    uri.push('/');
+                    uri.push_str(&percent_encode(segment, is_path_char));
+                }
+            }
+            None => uri.push('/'),
+        }
+
+        if let Some(arguments) = self.options.get(&OptionType::UriQuery.number()) {
+            for (i, argument) in arguments.iter().enumerate() {
+                uri.push(if i == 0 { '?' } else { '&' });
+                uri.push_str(&percent_encode(argument, is_query_char));
+            }
+        }
+        uri
+    }
+
+    fn get_first_option(&self, tp: OptionType) -> Option<&Vec<u8>> {
+        self.options.get(&tp.number()).and_then(|values| values.front())
+    }
+
+    fn get_block(&self, tp: OptionType) -> Option<BlockValue> {
+        let num = tp.number();
+        self.options
+            .get(&num)
+            .and_then(|list| list.front())
+            .and_then(|value| BlockValue::from_bytes(value))
+    }
+
+    fn set_block(&mut self, tp: OptionType, block: BlockValue) -> Result<(), PackageError> {
+        let mut list = LinkedList::new();
+        list.push_back(try!(block.to_bytes()));
+        self.set_option(tp, list);
+        Ok(())
+    }
+
+    /// Decodes a byte slice and construct the equivalent Packet.
+    pub fn from_bytes(buf: &[u8]) -> Result<Packet, ParseError> {
+        PacketRef::from_bytes(buf).map(|packet| packet.to_packet())
+    }
+
+    /// Returns the number of bytes of the encoded packet.
+    pub fn encoded_len(&self) -> usize {
+        let mut length = 4 + self.token.len();
+        let mut last_number = 0;
+        for (&number, value_list) in self.options.iter() {
+            for value in value_list.iter() {
+                length += 1 + extended_len(number - last_number) + extended_len(value.len()) +
+                          value.len();
+                last_number = number;
+            }
+        }
+        if self.has_payload() {
+            length += 1 + self.payload.len();
+        }
+        length
+    }
+
+    /// Encodes the packet into the start of the buffer, returning the number
+    ///   of bytes written.
+    pub fn encode_into(&self, buf: &mut [u8]) -> Result<usize, PackageError> {
+        let length = self.encoded_len();
+        if length > buf.len() {
+            return Err(PackageError::BufferTooSmall);
+        }
+
+        buf[0] = self.header.ver_type_tkl;
+        buf[1] = class_to_code(&self.header.code);
+        buf[2] = (self.header.message_id >> 8) as u8;
+        buf[3] = self.header.message_id as u8;
+        let mut idx = 4;
+        buf[idx..idx + self.token.len()].copy_from_slice(&self.token);
+        idx += self.token.len();
+
+        let mut last_number = 0;
+        for (&number, value_list) in self.options.iter() {
+            for value in value_list.iter() {
+                let delta = number - last_number;
+                if delta > MAX_EXTENDED || value.len() > MAX_EXTENDED {
+                    return Err(PackageError::InvalidPacketLength);
+                }
+                buf[idx] = extended_nibble(delta) << 4 | extended_nibble(value.len());
+                idx += 1;
+                idx += write_extended(&mut buf[idx..], delta);
+                idx += write_extended(&mut buf[idx..], value.len());
+                buf[idx..idx + value.len()].copy_from_slice(value);
+                idx += value.len();
+                last_number = number;
+            }
+        }
+
+        if self.has_payload() {
+            buf[idx] = 0xFF;
+            idx += 1;
+            buf[idx..idx + self.payload.len()].copy_from_slice(&self.payload);
+            idx += self.payload.len();
+        }
+        Ok(idx)
+    }
+
+    /// Returns a vector of bytes representing the Packet, of at most
+    ///   `DEFAULT_MAX_MESSAGE_SIZE` bytes.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, PackageError> {
+        self.to_bytes_with_limit(DEFAULT_MAX_MESSAGE_SIZE)
+    }
+
+    /// Returns a vector of bytes representing the Packet, of at most
+    ///   `max_size` bytes.
+    pub fn to_bytes_with_limit(&self, max_size: usize) -> Result<Vec<u8>, PackageError> {
+        let length = self.encoded_len();
+        if length > max_size {
+            return Err(PackageError::InvalidPacketLength);
+        }
+        let mut buf = vec![0; length];
+        try!(self.encode_into(&mut buf));
+        Ok(buf)
+    }
+
+    /// Empty messages never carry a payload.
+    fn has_payload(&self) -> bool {
+        self.header.code != PacketClass::Empty && self.payload.len() != 0
+    }
+}
+
+/// The 4-bit form of an option delta or length.
+fn extended_nibble(value: usize) -> u8 {
+    if value <= 12 {
+        value as u8
+    } else if value < 269 {
+        13
+    } else {
+        14
+    }
+}
+
+/// The number of bytes following the option header for a delta or length.
+fn extended_len(value: usize) -> usize {
+    if value <= 12 {
+        0
+    } else if value < 269 {
+        1
+    } else {
+        2
+    }
+}
+
+/// Writes the extended form of an option delta or length, returning the
+///   number of bytes written.
+fn write_extended(buf: &mut [u8], value: usize) -> usize {
+    if value > 12 && value < 269 {
+        buf[0] = (value - 13) as u8;
+    } else if value >= 269 {
+        let fix = (value - 269) as u16;
+        buf[0] = (fix >> 8) as u8;
+        buf[1] = (fix & 0xFF) as u8;
+    }
+    extended_len(value)
+}
+
+/// A packet borrowed from the buffer it was decoded from. Options are read
+///   from the buffer when iterated, so decoding does not allocate.
+#[derive(Debug, Clone)]
+pub struct PacketRef<'a> {
+    pub header: PacketHeader,
+    token: &'a [u8],
+    options: &'a [u8],
+    pub payload: &'a [u8],
+}
+
+impl<'a> PacketRef<'a> {
+    /// Decodes a byte slice, checking every option so that iterating them
+    ///   cannot fail.
+    pub fn from_bytes(buf: &'a [u8]) -> Result<PacketRef<'a>, ParseError> {
+        if buf.len() < 4 {
+            return Err(ParseError::InvalidHeader);
+        }
+        let header = PacketHeader::from_raw(&PacketHeaderRaw {
+            ver_type_tkl: buf[0],
+            code: buf[1],
+            message_id: (buf[2] as u16) << 8 | buf[3] as u16,
+        });
+        let token_length = header.get_token_length() as usize;
+        let options_start = 4 + token_length;
+
+        if token_length > 8 || options_start > buf.len() {
+            return Err(ParseError::InvalidTokenLength);
+        }
+
+        let mut idx = options_start;
+        while let Some((_, _, end)) = try!(read_option(buf, idx)) {
+            idx = end;
+        }
+
+        let payload = if idx < buf.len() { &buf[(idx + 1)..] } else { &buf[idx..] };
+
+        Ok(PacketRef {
+            header: header,
+            token: &buf[4..options_start],
+            options: &buf[options_start..idx],
+            payload: payload,
+        })
+    }
+
+    pub fn get_token(&self) -> &'a [u8] {
+        self.token
+    }
+
+    /// Iterates the options in the order they were encoded, as their number
+    ///   and value.
+    pub fn options(&self) -> OptionIter<'a> {
+        OptionIter {
+            buf: self.options,
+            idx: 0,
+            number: 0,
+        }
+    }
+
+    /// Returns the first value of the option.
+    pub fn first_option(&self, tp: OptionType) -> Option<&'a [u8]> {
+        let number = tp.number();
+        self.options().find(|&(n, _)| n == number).map(|(_, value)| value)
+    }
+
+    /// Copies the packet into an owned `Packet`.
+    pub fn to_packet(&self) -> Packet {
+        let mut packet = Packet::new();
+        packet.header = self.header.clone();
+        packet.token = self.token.to_vec();
+        for (number, value) in self.options() {
+            packet.add_raw_option(number, value.to_vec());
+        }
+        packet.payload = self.payload.to_vec();
+        packet
+    }
+}
+
+/// Iterator over the options of a `PacketRef`.
+#[derive(Debug, Clone)]
+pub struct OptionIter<'a> {
+    buf: &'a [u8],
+    idx: usize,
+    number: usize,
+}
+
+impl<'a> Iterator for OptionIter<'a> {
+    type Item = (usize, &'a [u8]);
+
+    fn next(&mut self) -> Option<(usize, &'a [u8])> {
+        // The options were checked by PacketRef::from_bytes
+        match read_option(self.buf, self.idx) {
+            Ok(Some((delta, start, end))) => {
+                self.number += delta;
+                self.idx = end;
+                Some((self.number, &self.buf[start..end]))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Reads the option at `idx`, returning its delta and the range of its value,
+///   or `None` at the payload marker or the end of the buffer.
+fn read_option(buf: &[u8], idx: usize) -> Result<Option<(usize, usize, usize)>, ParseError> {
+    if idx >= buf.len() || buf[idx] == 0xFF {
+        return Ok(None);
+    }
+
+    let byte = buf[idx];
+    let mut idx = idx + 1;
+    let delta = try!(read_extended(buf, &mut idx, (byte >> 4) as usize,
+                                   ParseError::InvalidOptionDelta));
+    let length = try!(read_extended(buf, &mut idx, (byte & 0xF) as usize,
+                                    ParseError::InvalidOptionLength));
+
+    let end = idx + length;
+    if end > buf.len() {
+        return Err(ParseError::InvalidOptionLength);
+    }
+    Ok(Some((delta, idx, end)))
+}
+
+/// Reads the extended option delta or length following a 4-bit `nibble`.
+fn read_extended(buf: &[u8],
+                 idx: &mut usize,
+                 nibble: usize,
+                 reserved: ParseError)
+                 -> Result<usize, ParseError> {
+    match nibble {
+        13 => {
+            if *idx >= buf.len() {
+                return Err(ParseError::InvalidOptionLength);
+            }
+            *idx += 1;
+            Ok(buf[*idx - 1] as usize + 13)
+        }
+        14 => {
+            if *idx + 1 >= buf.len() {
+                return Err(ParseError::InvalidOptionLength);
+            }
+            *idx += 2;
+            Ok(((buf[*idx - 2] as usize) << 8 | buf[*idx - 1] as usize) + 269)
+        }
+        15 => Err(reserved),
+        _ => Ok(nibble),
+    }
+}
+
+/// The reason a `PacketBuilder` could not build a packet.
+#[derive(Debug, PartialEq)]
+pub enum BuildError {
+    /// The version is not 1.
+    InvalidVersion(u8),
+    /// The type is `PacketType::Invalid`.
+    InvalidType,
+    /// The code is not of the form "c.dd" with a class up to 7 and a detail
+    ///   up to 31.
+    InvalidCode(String),
+    /// The token is longer than 8 bytes.
+    TokenTooLong(usize),
+    /// A value of the option breaks its format or length limits.
+    InvalidOption(usize, OptionError),
+    /// An option that is not repeatable occurs more than once.
+    RepeatedOption(usize),
+    /// An Empty message (code 0.00) has a token, options or a payload.
+    EmptyMessageWithContent,
+    /// A Reset message has a code other than 0.00.
+    NonEmptyReset,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BuildError::InvalidVersion(version) => write!(f, "invalid version {}", version),
+            BuildError::InvalidType => write!(f, "invalid message type"),
+            BuildError::InvalidCode(ref code) => write!(f, "invalid code {:?}", code),
+            BuildError::TokenTooLong(length) => {
+                write!(f, "token of {} bytes is longer than 8 bytes", length)
+            }
+            BuildError::InvalidOption(number, ref error) => {
+                write!(f, "invalid value of option {}: {:?}", number, error)
+            }
+            BuildError::RepeatedOption(number) => {
+                write!(f, "option {} is not repeatable", number)
+            }
+            BuildError::EmptyMessageWithContent => {
+                write!(f, "empty message with a token, options or a payload")
+            }
+            BuildError::NonEmptyReset => write!(f, "reset message with a code"),
+        }
+    }
+}
+
+/// Parses a code of the form "c.dd", e.g. "2.05".
+pub fn parse_code(code: &str) -> Result<PacketClass, BuildError> {
+    let invalid = || BuildError::InvalidCode(code.to_string());
+    let mut parts = code.splitn(2, '.');
+    let class_code = try!(parts.next().and_then(|c| c.parse::<u8>().ok()).ok_or_else(&invalid));
+    let detail_code = try!(parts.next().and_then(|d| d.parse::<u8>().ok()).ok_or_else(&invalid));
+    if class_code > 7 || detail_code > 31 {
+        return Err(invalid());
+    }
+    Ok(code_to_class(&(class_code << 5 | detail_code)))
+}
+
+/// Builds a packet from values that may be invalid, e.g. read from a
+///   configuration, reporting the first invalid one instead of panicking.
+///
+/// ```
+/// use coap::packet::{PacketBuilder, PacketType, OptionType};
+///
+/// let packet = PacketBuilder::new()
+///     .message_type(PacketType::Confirmable)
+///     .code("0.01")
+///     .message_id(1)
+///     .token(vec![0x51, 0x55])
+///     .option(OptionType::UriPath, b"temperature".to_vec())
+///     .build()
+///     .unwrap();
+/// assert_eq!(packet.header.get_code(), "0.01");
+/// ```
+pub struct PacketBuilder {
+    version: u8,
+    message_type: PacketType,
+    code: PacketClass,
+    message_id: u16,
+    token: Vec<u8>,
+    options: BTreeMap<usize, LinkedList<Vec<u8>>>,
+    payload: Vec<u8>,
+    error: Option<BuildError>,
+}
+
+impl PacketBuilder {
+    /// Starts an Empty Confirmable message of version 1 with message ID 0.
+    pub fn new() -> PacketBuilder {
+        PacketBuilder {
+            version: 1,
+            message_type: PacketType::Confirmable,
+            code: PacketClass::Empty,
+            message_id: 0,
+            token: Vec::new(),
+            options: BTreeMap::new(),
+            payload: Vec::new(),
+            error: None,
+        }
+    }
+
+    pub fn version(mut self, version: u8) -> PacketBuilder {
+        self.version = version;
+        self
+    }
+
+    pub fn message_type(mut self, message_type: PacketType) -> PacketBuilder {
+        self.message_type = message_type;
+        self
+    }
+
+    /// Sets the code from its "c.dd" form, e.g. "0.01" for GET.
+    pub fn code(mut self, code: &str) -> PacketBuilder {
+        match parse_code(code) {
+            Ok(class) => self.code = class,
+            Err(error) => self.fail(error),
+        }
+        self
+    }
+
+    pub fn class(mut self, class: PacketClass) -> PacketBuilder {
+        self.code = class;
+        self
+    }
+
+    pub fn message_id(mut self, message_id: u16) -> PacketBuilder {
+        self.message_id = message_id;
+        self
+    }
+
+    pub fn token(mut self, token: Vec<u8>) -> PacketBuilder {
+        self.token = token;
+        self
+    }
+
+    /// Adds an encoded value of the option.
+    pub fn option(self, tp: OptionType, value: Vec<u8>) -> PacketBuilder {
+        self.raw_option(tp.number(), value)
+    }
+
+    /// Adds a value of the option, encoded according to its format.
+    pub fn option_value(mut self, tp: OptionType, value: OptionValue) -> PacketBuilder {
+        match Packet::encode_option_value(&tp, &value) {
+            Ok(bytes) => return self.raw_option(tp.number(), bytes),
+            Err(error) => self.fail(BuildError::InvalidOption(tp.number(), error)),
+        }
+        self
+    }
+
+    /// Adds an encoded value of the option with the number. Values of options
+    ///   unknown to this library are not checked.
+    pub fn raw_option(mut self, number: usize, value: Vec<u8>) -> PacketBuilder {
+        self.options.entry(number).or_insert_with(LinkedList::new).push_back(value);
+        self
+    }
+
+    pub fn payload(mut self, payload: Vec<u8>) -> PacketBuilder {
+        self.payload = payload;
+        self
+    }
+
+    /// Checks the values and builds the packet.
+    pub fn build(self) -> Result<Packet, BuildError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        if self.version != 1 {
+            return Err(BuildError::InvalidVersion(self.version));
+        }
+        if self.message_type == PacketType::Invalid {
+            return Err(BuildError::InvalidType);
+        }
+        if self.token.len() > 8 {
+            return Err(BuildError::TokenTooLong(self.token.len()));
+        }
+        for (&number, values) in self.options.iter() {
+            let definition = match OptionType::from_number(number) {
+                Some(tp) => tp.definition(),
+                None => continue,
+            };
+            if !definition.repeatable && values.len() > 1 {
+                return Err(BuildError::RepeatedOption(number));
+            }
+            for value in values.iter() {
+                if value.len() < definition.min_length || value.len() > definition.max_length {
+                    return Err(BuildError::InvalidOption(number, OptionError::InvalidLength));
+                }
+            }
+        }
+        if self.code == PacketClass::Empty {
+            if !self.token.is_empty() || !self.options.is_empty() || !self.payload.is_empty() {
+                return Err(BuildError::EmptyMessageWithContent);
+            }
+        } else if self.message_type == PacketType::Reset {
+            return Err(BuildError::NonEmptyReset);
+        }
+
+        let mut packet = Packet::new();
+        packet.header.set_version(self.version);
+        packet.header.set_type(self.message_type);
+        packet.header.code = self.code;
+        packet.header.set_message_id(self.message_id);
+        packet.set_token(self.token);
+        packet.options = self.options;
+        packet.payload = self.payload;
+        Ok(packet)
+    }
+
+    /// Keeps the first error, to be reported by `build`.
+    fn fail(&mut self, error: BuildError) {
+        if self.error.is_none() {
+            self.error = Some(error);
+        }
+    }
+}
+
+/// Resolves the "." and ".." segments of an absolute path
+///   ([RFC 3986 section 5.2.4][spec]).
+///
+/// [spec]: https://tools.ietf.org/html/rfc3986#section-5.2.4
+fn remove_dot_segments(path: &str) -> String {
+    if path.is_empty() {
+        return String::new();
+    }
+    let segments: Vec<&str> = path[1..].split('/').collect();
+    let mut output: Vec<&str> = Vec::new();
+    for (i, &segment) in segments.iter().enumerate() {
+        match segment {
+            "." => {}
+            ".." => {
+                output.pop();
+            }
+            _ => output.push(segment),
+        }
+        // A path ending in a dot segment keeps its trailing slash
+        if i == segments.len() - 1 && (segment == "." || segment == "..") {
+            output.push("");
+        }
+    }
+    format!("/{}", output.join("/"))
+}
+
+fn percent_decode(value: &str) -> Result<Vec<u8>, UriError> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let digits = match bytes.get(i + 1..i + 3) {
+                Some(digits) if digits.iter().all(|b| b.is_ascii_hexdigit()) => digits,
+                _ => return Err(UriError::InvalidPercentEncoding),
+            };
+            let byte = digits.iter()
+                .fold(0, |acc, &b| acc << 4 | (b as char).to_digit(16).unwrap() as u8);
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(decoded)
+}
+
+fn percent_encode(value: &[u8], allowed: fn(u8) -> bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for &byte in value {
+        if allowed(byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// The unreserved and sub-delims characters of [RFC 3986][spec].
+///
+/// [spec]: https://tools.ietf.org/html/rfc3986#section-2
+fn is_host_char(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=".contains(&byte)
+}
+
+fn is_path_char(byte: u8) -> bool {
+    is_host_char(byte) || byte == b':' || byte == b'@'
+}
+
+fn is_query_char(byte: u8) -> bool {
+    (is_path_char(byte) || byte == b'/' || byte == b'?') && byte != b'&'
+}
+
+/// Convert a request to a response, see `Packet::default_response_to`.
+pub fn auto_response(request_packet: &Packet) -> Option<Packet> {
+    Packet::default_response_to(request_packet)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::collections::LinkedList;
+
+    #[test]
+    fn test_header_codes() {
+        for code in 0..256 {
+            let code = code as u8;
+            let class = code_to_class(&code);
+            let code_str = code_to_str(&code);
+            let class_str = class_to_str(&class);
+
+            // Unknown codes are kept as they are
+            assert_eq!(class_to_code(&class), code);
+            assert_eq!(code_str, class_str);
+        }
+
+        assert_eq!(code_to_class(&0xA0),
+                   PacketClass::Response(Responses::InternalServerError));
+        assert_eq!(code_to_str(&0xA0), "5.00");
+        assert_eq!(code_to_class(&0x9D), PacketClass::Response(Responses::TooManyRequests));
+        assert_eq!(code_to_class(&0x46), PacketClass::Unknown(2, 6));
+        assert_eq!(code_to_class(&0x08), PacketClass::Unknown(0, 8));
+    }
+
+    #[test]
+    fn test_unknown_code_round_trip() {
+        let buf = [0x40, 0xE1, 0x00, 0x01];
+        let packet = Packet::from_bytes(&buf).unwrap();
+        assert_eq!(packet.header.code, PacketClass::Unknown(7, 1));
+        assert_eq!(packet.header.get_code(), "7.01");
+        assert_eq!(packet.to_bytes().unwrap(), buf.to_vec());
+    }
+
+    #[test]
+    fn test_decode_packet_with_options() {
         let buf = [0x44, 0x01, 0x84, 0x9e, 0x51, 0x55, 0x77, 0xe8, 0xb2, 0x48, 0x69, 0x04, 0x54,
                    0x65, 0x73, 0x74, 0x43, 0x61, 0x3d, 0x31];
         let packet = Packet::from_bytes(&buf);
@@ -725,6 +1940,436 @@ mod test {
                         0x6C, 0x6F]);
     }
 
+    #[test]
+    fn test_encode_into() {
+        let mut packet = Packet::new();
+        packet.header.code = PacketClass::Request(Requests::Post);
+        packet.add_option(OptionType::Block1, vec![0x0E]);
+        packet.add_raw_option(600, vec![0xAB; 300]);
+        packet.payload = b"x".to_vec();
+
+        let mut expected = vec![0x00, 0x02, 0x00, 0x00, 0xD1, 0x0E, 0x0E, 0xEE, 0x01, 0x30, 0x00,
+                                0x1F];
+        expected.extend(vec![0xAB; 300]);
+        expected.extend(vec![0xFF, b'x']);
+        assert_eq!(packet.encoded_len(), expected.len());
+        assert_eq!(packet.to_bytes().unwrap(), expected);
+
+        let mut buf = [0; 1280];
+        assert_eq!(packet.encode_into(&mut buf).unwrap(), expected.len());
+        assert_eq!(&buf[..expected.len()], &expected[..]);
+        match packet.encode_into(&mut buf[..expected.len() - 1]) {
+            Err(PackageError::BufferTooSmall) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        assert!(packet.to_bytes_with_limit(expected.len()).is_ok());
+        match packet.to_bytes_with_limit(expected.len() - 1) {
+            Err(PackageError::InvalidPacketLength) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        packet.header.code = PacketClass::Empty;
+        packet.payload = vec![0; 2000];
+        assert_eq!(packet.encoded_len(), expected.len() - 2);
+
+        packet.add_raw_option(700, vec![0; 2000]);
+        assert!(packet.to_bytes().is_err());
+        assert!(packet.to_bytes_with_limit(4000).is_ok());
+    }
+
+    #[test]
+    fn test_block_value() {
+        let block = BlockValue::new(0, false, 0).unwrap();
+        assert_eq!(block.to_bytes().unwrap(), Vec::<u8>::new());
+        assert_eq!(BlockValue::from_bytes(&[]), Some(block));
+
+        let block = BlockValue::new(1, true, 6).unwrap();
+        assert_eq!(block.size(), 1024);
+        assert_eq!(block.offset(), 1024);
+        assert_eq!(block.to_bytes().unwrap(), vec![0x1E]);
+        assert_eq!(BlockValue::from_bytes(&[0x1E]), Some(block));
+
+        let block = BlockValue::new(0xFFFFF, false, 2).unwrap();
+        assert_eq!(block.to_bytes().unwrap(), vec![0xFF, 0xFF, 0xF2]);
+        assert_eq!(BlockValue::from_bytes(&[0xFF, 0xFF, 0xF2]), Some(block));
+
+        assert_eq!(BlockValue::new(0x100000, false, 2), None);
+        assert_eq!(BlockValue::new(0, false, 7), None);
+        let block = BlockValue {
+            num: 0x100000,
+            more: false,
+            size_exponent: 2,
+        };
+        assert!(block.to_bytes().is_err());
+        assert!(Packet::new().set_block2(block).is_err());
+
+        assert_eq!(BlockValue::from_bytes(&[0x0F]), None);
+        assert_eq!(BlockValue::from_bytes(&[0, 0, 0, 0]), None);
+        assert_eq!(BlockValue::size_exponent_of(512), Some(5));
+        assert_eq!(BlockValue::size_exponent_of(100), None);
+    }
+
+    #[test]
+    fn test_block2_option() {
+        let mut packet = Packet::new();
+        assert_eq!(packet.get_block2(), None);
+        let block = BlockValue::new(2, true, 4).unwrap();
+        packet.set_block2(block).unwrap();
+        assert_eq!(packet.get_block2(), Some(block));
+        packet.clear_option(OptionType::Block2);
+        assert_eq!(packet.get_block2(), None);
+    }
+
+    #[test]
+    fn test_size1_option() {
+        let mut packet = Packet::new();
+        assert_eq!(packet.get_size1(), None);
+        packet.set_size1(0);
+        assert_eq!(packet.get_option(OptionType::Size1).unwrap().front().unwrap().len(), 0);
+        assert_eq!(packet.get_size1(), Some(0));
+        packet.set_size1(70000);
+        assert_eq!(*packet.get_option(OptionType::Size1).unwrap().front().unwrap(),
+                   vec![0x01, 0x11, 0x70]);
+        assert_eq!(packet.get_size1(), Some(70000));
+    }
+
+    #[test]
+    fn test_option_values() {
+        assert_eq!(OptionValue::UInt(0).to_bytes(), Vec::<u8>::new());
+        assert_eq!(OptionValue::UInt(0x0100).to_bytes(), vec![1, 0]);
+        assert_eq!(OptionValue::from_bytes(OptionFormat::UInt, &[1, 0]),
+                   Some(OptionValue::UInt(0x0100)));
+        assert_eq!(OptionValue::from_bytes(OptionFormat::UInt, &[1, 0, 0, 0, 0]), None);
+        assert_eq!(OptionValue::from_bytes(OptionFormat::Empty, &[1]), None);
+        assert_eq!(OptionValue::from_bytes(OptionFormat::String, &[0xFF]), None);
+
+        let mut packet = Packet::new();
+        assert_eq!(packet.set_option_value(OptionType::ETag, OptionValue::Opaque(vec![])),
+                   Err(OptionError::InvalidLength));
+        assert_eq!(packet.set_option_value(OptionType::ETag, OptionValue::Opaque(vec![0; 9])),
+                   Err(OptionError::InvalidLength));
+        assert_eq!(packet.add_option_value(OptionType::MaxAge, OptionValue::Empty),
+                   Err(OptionError::InvalidFormat));
+        assert!(packet.get_option(OptionType::ETag).is_none());
+
+        packet.add_option_value(OptionType::ETag, OptionValue::Opaque(vec![1])).unwrap();
+        packet.add_option(OptionType::ETag, vec![0; 9]);
+        packet.add_option_value(OptionType::ETag, OptionValue::Opaque(vec![2])).unwrap();
+        assert_eq!(packet.get_option_values(OptionType::ETag),
+                   vec![OptionValue::Opaque(vec![1]), OptionValue::Opaque(vec![2])]);
+    }
+
+    #[test]
+    fn test_option_accessors() {
+        let mut packet = Packet::new();
+        assert_eq!(packet.get_content_format(), None);
+        packet.set_content_format(50);
+        assert_eq!(packet.get_option(OptionType::ContentFormat).unwrap().front(),
+                   Some(&vec![50]));
+        assert_eq!(packet.get_content_format(), Some(50));
+
+        packet.set_max_age(0);
+        assert_eq!(packet.get_option(OptionType::MaxAge).unwrap().front(), Some(&vec![]));
+        assert_eq!(packet.get_max_age(), Some(0));
+        packet.set_accept(0x1234);
+        assert_eq!(packet.get_accept(), Some(0x1234));
+        packet.set_uri_port(61616);
+        assert_eq!(packet.get_uri_port(), Some(61616));
+
+        packet.set_observe(0x1FFFFFF);
+        assert_eq!(packet.get_observe(), Some(0xFFFFFF));
+        packet.clear_option(OptionType::Observe);
+        packet.add_option(OptionType::Observe, vec![1, 0, 0, 0]);
+        assert_eq!(packet.get_observe(), None);
+
+        assert_eq!(packet.set_uri_host(""), Err(OptionError::InvalidLength));
+        packet.set_uri_host("example.com").unwrap();
+        assert_eq!(packet.get_uri_host(), Some("example.com".to_string()));
+
+        packet.set_uri_path_string("/sensors/temp").unwrap();
+        assert_eq!(packet.get_option(OptionType::UriPath).unwrap().len(), 2);
+        assert_eq!(packet.get_uri_path_string(), "sensors/temp");
+        let long_segment = String::from_utf8(vec![b'a'; 256]).unwrap();
+        assert_eq!(packet.set_uri_path_string(&long_segment),
+                   Err(OptionError::InvalidLength));
+        assert_eq!(packet.get_uri_path_string(), "sensors/temp");
+        packet.set_uri_path_string("/").unwrap();
+        assert!(packet.get_option(OptionType::UriPath).is_none());
+    }
+
+    #[test]
+    fn test_option_classification() {
+        assert!(is_critical_option(OptionType::UriHost.number()));
+        assert!(is_unsafe_option(OptionType::UriHost.number()));
+        assert!(!is_critical_option(OptionType::ETag.number()));
+        assert!(!is_unsafe_option(OptionType::ETag.number()));
+        assert!(!is_no_cache_key_option(OptionType::ETag.number()));
+        assert!(is_unsafe_option(OptionType::MaxAge.number()));
+        assert!(is_no_cache_key_option(OptionType::Size1.number()));
+        assert!(is_critical_option(OptionType::Block1.number()));
+        assert_eq!(OptionType::from_number(60), Some(OptionType::Size1));
+        assert_eq!(OptionType::from_number(61), None);
+    }
+
+    #[test]
+    fn test_raw_options() {
+        let mut packet = Packet::new();
+        packet.header.set_version(1);
+        packet.add_option(OptionType::UriPath, b"a".to_vec());
+        packet.add_raw_option(65000, vec![1, 2]);
+        packet.add_raw_option(65000, vec![3]);
+        assert_eq!(packet.get_option_numbers(), vec![11, 65000]);
+
+        let parsed = Packet::from_bytes(&packet.to_bytes().unwrap()).unwrap();
+        let values: Vec<Vec<u8>> = parsed.get_raw_option(65000).unwrap().into_iter().collect();
+        assert_eq!(values, vec![vec![1, 2], vec![3]]);
+        assert_eq!(parsed.get_raw_option(11), parsed.get_option(OptionType::UriPath));
+
+        packet.clear_raw_option(65000);
+        assert!(packet.get_raw_option(65000).is_none());
+    }
+
+    #[test]
+    fn test_option_registry() {
+        let mut registry = OptionRegistry::new();
+        assert_eq!(registry.get(4).unwrap().name, "ETag");
+        assert!(registry.get(4).unwrap().repeatable);
+        assert_eq!(registry.get_by_name("Content-Format").unwrap().number, 12);
+        assert_eq!(registry.get(28).unwrap().name, "Size2");
+        assert_eq!(registry.get(292).unwrap().name, "Request-Tag");
+        assert!(registry.get(292).unwrap().repeatable);
+        assert!(!registry.get(28).unwrap().repeatable);
+        assert!(!registry.is_known(65000));
+
+        let experimental = OptionDefinition::new(65000, "Experimental", OptionFormat::UInt,
+                                                 false, 0, 2);
+        registry.register(experimental.clone()).unwrap();
+        assert_eq!(registry.register(experimental.clone()),
+                   Err(OptionError::AlreadyRegistered));
+        let renamed = OptionDefinition::new(65001, "ETag", OptionFormat::Opaque, true, 1, 8);
+        assert_eq!(registry.register(renamed), Err(OptionError::AlreadyRegistered));
+        assert_eq!(registry.get_by_name("Experimental"), Some(&experimental));
+
+        let mut packet = Packet::new();
+        packet.add_defined_option(&experimental, OptionValue::UInt(1)).unwrap();
+        packet.add_defined_option(&experimental, OptionValue::UInt(2)).unwrap();
+        assert_eq!(packet.add_defined_option(&experimental, OptionValue::UInt(0x10000)),
+                   Err(OptionError::InvalidLength));
+        assert_eq!(packet.get_defined_option(&experimental), vec![OptionValue::UInt(2)]);
+    }
+
+    fn uri_options(packet: &Packet, tp: OptionType) -> Vec<Vec<u8>> {
+        packet.get_option(tp).map_or(Vec::new(), |values| values.into_iter().collect())
+    }
+
+    #[test]
+    fn test_decompose_uri() {
+        let mut packet = Packet::new();
+        let endpoint = packet.set_uri("coap://EXAMPLE.com/%7Esensors/temp.xml?a=1&b%26c").unwrap();
+        assert_eq!(endpoint, ("example.com".to_string(), 5683));
+        assert_eq!(uri_options(&packet, OptionType::UriHost), vec![b"example.com".to_vec()]);
+        assert_eq!(uri_options(&packet, OptionType::UriPath),
+                   vec![b"~sensors".to_vec(), b"temp.xml".to_vec()]);
+        assert_eq!(uri_options(&packet, OptionType::UriQuery),
+                   vec![b"a=1".to_vec(), b"b&c".to_vec()]);
+
+        let endpoint = packet.set_uri("coap://[::1]:61616/").unwrap();
+        assert_eq!(endpoint, ("::1".to_string(), 61616));
+        assert!(packet.get_option(OptionType::UriHost).is_none());
+        assert!(packet.get_option(OptionType::UriPath).is_none());
+        assert!(packet.get_option(OptionType::UriQuery).is_none());
+
+        packet.set_uri("coaps://127.0.0.1/a//b/").unwrap();
+        assert!(packet.get_option(OptionType::UriHost).is_none());
+        assert_eq!(uri_options(&packet, OptionType::UriPath),
+                   vec![b"a".to_vec(), b"".to_vec(), b"b".to_vec(), b"".to_vec()]);
+        assert_eq!(packet.set_uri("coaps://127.0.0.1").unwrap().1, 5684);
+
+        packet.set_uri("coap://example.com/a/./../b/c/..?x").unwrap();
+        assert_eq!(uri_options(&packet, OptionType::UriPath), vec![b"b".to_vec(), b"".to_vec()]);
+        packet.set_uri("coap://example.com/../a/%2E%2E").unwrap();
+        assert_eq!(uri_options(&packet, OptionType::UriPath),
+                   vec![b"a".to_vec(), b"..".to_vec()]);
+        packet.set_uri("coap://example.com/a/..").unwrap();
+        assert!(packet.get_option(OptionType::UriPath).is_none());
+
+        packet.set_uri("coap://example.com/a?").unwrap();
+        assert_eq!(uri_options(&packet, OptionType::UriPath), vec![b"a".to_vec()]);
+        assert!(packet.get_option(OptionType::UriQuery).is_none());
+
+        assert_eq!(packet.set_uri("/relative"), Err(UriError::NotAbsolute));
+        assert_eq!(packet.set_uri("http://example.com"), Err(UriError::UnsupportedScheme));
+        assert_eq!(packet.set_uri("coap://example.com/#a"), Err(UriError::HasFragment));
+        assert_eq!(packet.set_uri("coap://:5683/"), Err(UriError::InvalidHost));
+        let uri = format!("coap://{}/", "a".repeat(256));
+        assert_eq!(packet.set_uri(&uri), Err(UriError::InvalidHost));
+        assert!(packet.set_uri(&format!("coap://{}/", "a".repeat(255))).is_ok());
+        assert_eq!(packet.set_uri("coap://example.com:x/"), Err(UriError::InvalidPort));
+        assert_eq!(packet.set_uri("coap://example.com/%7"),
+                   Err(UriError::InvalidPercentEncoding));
+    }
+
+    #[test]
+    fn test_compose_uri() {
+        let mut packet = Packet::new();
+        assert_eq!(packet.get_uri("127.0.0.1", 5683), "coap://127.0.0.1/");
+        assert_eq!(packet.get_uri("::1", 61616), "coap://[::1]:61616/");
+
+        let uri = "coap://example.com:61616/%7Esensors/a%20b/?x=1&y%26z";
+        packet.set_uri(uri).unwrap();
+        packet.add_option(OptionType::UriPort, vec![0xF0, 0x50]);
+        assert_eq!(packet.get_uri("10.0.0.1", 5683),
+                   "coap://example.com:61520/~sensors/a%20b/?x=1&y%26z");
+    }
+
+    #[test]
+    fn test_builder() {
+        let packet = PacketBuilder::new()
+            .message_type(PacketType::Confirmable)
+            .code("0.01")
+            .message_id(33950)
+            .token(vec![0x51, 0x55, 0x77, 0xE8])
+            .option(OptionType::UriPath, b"Hi".to_vec())
+            .option(OptionType::UriPath, b"Test".to_vec())
+            .option(OptionType::UriQuery, b"a=1".to_vec())
+            .build()
+            .unwrap();
+        let buf = [0x44, 0x01, 0x84, 0x9e, 0x51, 0x55, 0x77, 0xe8, 0xb2, 0x48, 0x69, 0x04, 0x54,
+                   0x65, 0x73, 0x74, 0x43, 0x61, 0x3d, 0x31];
+        assert_eq!(packet.to_bytes().unwrap(), buf.to_vec());
+
+        let packet = PacketBuilder::new()
+            .class(PacketClass::Response(Responses::Content))
+            .option_value(OptionType::ContentFormat, OptionValue::UInt(50))
+            .payload(b"{}".to_vec())
+            .build()
+            .unwrap();
+        assert_eq!(packet.get_content_format(), Some(50));
+        assert_eq!(packet.payload, b"{}".to_vec());
+
+        let ping = PacketBuilder::new().build().unwrap();
+        assert_eq!(ping.to_bytes().unwrap(), vec![0x40, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_builder_errors() {
+        let request = || PacketBuilder::new().code("0.01");
+        assert_eq!(request().version(2).build().unwrap_err(),
+                   BuildError::InvalidVersion(2));
+        assert_eq!(request().message_type(PacketType::Invalid).build().unwrap_err(),
+                   BuildError::InvalidType);
+        assert_eq!(request().token(vec![0; 9]).build().unwrap_err(),
+                   BuildError::TokenTooLong(9));
+        for code in &["", "1", "a.01", "8.00", "2.32", "2.05.1"] {
+            assert_eq!(PacketBuilder::new().code(code).build().unwrap_err(),
+                       BuildError::InvalidCode(code.to_string()));
+        }
+        assert_eq!(request().code("x").code("y").build().unwrap_err(),
+                   BuildError::InvalidCode("x".to_string()));
+        assert_eq!(request().option(OptionType::IfNoneMatch, vec![0]).build().unwrap_err(),
+                   BuildError::InvalidOption(5, OptionError::InvalidLength));
+        assert_eq!(request()
+                       .option_value(OptionType::UriHost, OptionValue::UInt(1))
+                       .build()
+                       .unwrap_err(),
+                   BuildError::InvalidOption(3, OptionError::InvalidFormat));
+        assert_eq!(request()
+                       .option(OptionType::MaxAge, vec![1])
+                       .option(OptionType::MaxAge, vec![2])
+                       .build()
+                       .unwrap_err(),
+                   BuildError::RepeatedOption(14));
+        assert!(request()
+            .option(OptionType::RequestTag, vec![1])
+            .option(OptionType::RequestTag, vec![2])
+            .build()
+            .is_ok());
+        assert!(request().raw_option(65000, vec![0; 2000]).build().is_ok());
+        assert_eq!(PacketBuilder::new().token(vec![1]).build().unwrap_err(),
+                   BuildError::EmptyMessageWithContent);
+        assert_eq!(request().message_type(PacketType::Reset).build().unwrap_err(),
+                   BuildError::NonEmptyReset);
+    }
+
+    #[test]
+    fn test_packet_ref() {
+        let buf = [0x44, 0x01, 0x84, 0x9e, 0x51, 0x55, 0x77, 0xe8, 0xb2, 0x48, 0x69, 0x04, 0x54,
+                   0x65, 0x73, 0x74, 0x43, 0x61, 0x3d, 0x31, 0xFF, 0x7B, 0x7D];
+        let packet = PacketRef::from_bytes(&buf).unwrap();
+        assert_eq!(packet.header.code, PacketClass::Request(Requests::Get));
+        assert_eq!(packet.header.get_message_id(), 33950);
+        assert_eq!(packet.get_token(), &[0x51, 0x55, 0x77, 0xE8]);
+        assert_eq!(packet.options().collect::<Vec<_>>(),
+                   vec![(11, &b"Hi"[..]), (11, &b"Test"[..]), (15, &b"a=1"[..])]);
+        assert_eq!(packet.first_option(OptionType::UriPath), Some(&b"Hi"[..]));
+        assert_eq!(packet.first_option(OptionType::ETag), None);
+        assert_eq!(packet.payload, b"{}");
+        assert_eq!(packet.to_packet(), Packet::from_bytes(&buf).unwrap());
+
+        let mut request = Packet::new();
+        request.add_option(OptionType::RequestTag, vec![0x01]);
+        request.add_option(OptionType::Size1, vec![0x01; 4]);
+        let bytes = request.to_bytes().unwrap();
+        let packet = PacketRef::from_bytes(&bytes).unwrap();
+        assert_eq!(packet.options().map(|(number, _)| number).collect::<Vec<_>>(),
+                   vec![60, 292]);
+        assert_eq!(packet.to_packet(), request);
+
+        assert!(PacketRef::from_bytes(&[0x40, 0x01, 0x00]).is_err());
+        assert!(PacketRef::from_bytes(&[0x40, 0x01, 0x00, 0x01, 0xE0, 0x01]).is_err());
+        assert!(PacketRef::from_bytes(&[0x40, 0x01, 0x00, 0x01, 0x03, 0x01]).is_err());
+    }
+
+    #[test]
+    fn test_response_to() {
+        let mut request = Packet::new();
+        request.header.set_type(PacketType::Confirmable);
+        request.header.code = PacketClass::Request(Requests::Get);
+        request.header.set_message_id(7);
+        request.set_token(vec![0x51, 0x55]);
+        request.add_option(OptionType::UriPath, b"secret".to_vec());
+        request.payload = b"body".to_vec();
+
+        let response = Packet::default_response_to(&request).unwrap();
+        assert_eq!(response.header.get_type(), PacketType::Acknowledgement);
+        assert_eq!(response.header.code, PacketClass::Response(Responses::Content));
+        assert_eq!(response.header.get_message_id(), 7);
+        assert_eq!(*response.get_token(), vec![0x51, 0x55]);
+        assert!(response.get_option_numbers().is_empty());
+        assert!(response.payload.is_empty());
+
+        let response = Packet::response_to(&request, Responses::NotFound).unwrap();
+        assert_eq!(response.header.code, PacketClass::Response(Responses::NotFound));
+        assert!(response.payload.is_empty());
+
+        request.header.set_type(PacketType::NonConfirmable);
+        for &(ref method, ref code) in &[(Requests::Post, Responses::Changed),
+                                         (Requests::Put, Responses::Changed),
+                                         (Requests::Delete, Responses::Deleted),
+                                         (Requests::Fetch, Responses::Content),
+                                         (Requests::IPatch, Responses::Changed)] {
+            request.header.code = PacketClass::Request(method.clone());
+            let response = Packet::default_response_to(&request).unwrap();
+            assert_eq!(response.header.get_type(), PacketType::NonConfirmable);
+            assert_eq!(response.header.code, PacketClass::Response(code.clone()));
+        }
+        request.header.code = PacketClass::Unknown(0, 8);
+        assert_eq!(Packet::default_response_to(&request).unwrap().header.code,
+                   PacketClass::Response(Responses::MethodNotAllowed));
+
+        let mut ping = Packet::new();
+        ping.header.set_type(PacketType::Confirmable);
+        assert!(Packet::default_response_to(&ping).is_none());
+        ping.header.set_type(PacketType::Reset);
+        assert!(Packet::default_response_to(&ping).is_none());
+        request.header.code = PacketClass::Request(Requests::Get);
+        request.header.set_type(PacketType::Reset);
+        assert!(Packet::response_to(&request, Responses::Content).is_none());
+    }
+
+    // The generator of quickcheck 0.2 needs `rand`, a dependency of `std`
+    #[cfg(feature = "std")]
     #[test]
     fn test_malicious_packet() {
         use rand;
@@ -739,9 +2384,24 @@ mod test {
                 Err(_) => TestResult::passed(),
             }
         }
+        fn run_ref(x: Vec<u8>) -> TestResult {
+            match PacketRef::from_bytes(&x[..]) {
+                Ok(packet) => {
+                    let options_length: usize = packet.options().map(|(_, v)| v.len()).sum();
+                    TestResult::from_bool(packet.get_token().len() ==
+                                          packet.header.get_token_length() as usize &&
+                                          options_length + packet.payload.len() <= x.len())
+                }
+                Err(_) => TestResult::passed(),
+            }
+        }
+        QuickCheck::new()
+            .tests(10000)
+            .gen(StdGen::new(rand::thread_rng(), 1500))
+            .quickcheck(run as fn(Vec<u8>) -> TestResult);
         QuickCheck::new()
             .tests(10000)
             .gen(StdGen::new(rand::thread_rng(), 1500))
-            .quickcheck(run as fn(Vec<u8>) -> TestResult)
+            .quickcheck(run_ref as fn(Vec<u8>) -> TestResult)
     }
 }
@@ -0,0 +1,272 @@
+//! Dispatching of requests to handlers by resource path and method.
+//!
+//! ```no_run
+//! extern crate coap;
+//!
+//! use coap::packet::*;
+//! use coap::CoAPServer;
+//! use coap::router::Router;
+//!
+//! fn get_temperature(_: Packet, response: Option<Packet>) -> Option<Packet> {
+//!     response.map(|mut packet| {
+//!         packet.set_payload(b"21.5".to_vec());
+//!         packet
+//!     })
+//! }
+//!
+//! fn main() {
+//!     let mut sensors = Router::new();
+//!     sensors.get("/temperature", get_temperature);
+//!
+//!     let mut router = Router::new();
+//!     router.mount("/sensors", sensors);
+//!
+//!     let mut server = CoAPServer::new("127.0.0.1:5683").unwrap();
+//!     server.handle(router).unwrap();
+//! }
+//! ```
+
+use std::collections::HashMap;
+use packet::{Packet, PacketClass, Requests, Responses, OptionType};
+use server::CoAPHandler;
+
+struct Node {
+    methods: Vec<(Requests, Box<dyn CoAPHandler>)>,
+    children: HashMap<String, Node>,
+}
+
+impl Node {
+    fn new() -> Node {
+        Node {
+            methods: Vec::new(),
+            children: HashMap::new(),
+        }
+    }
+
+    fn merge(&mut self, other: Node) {
+        for (method, handler) in other.methods {
+            self.methods.retain(|&(ref m, _)| *m != method);
+            self.methods.push((method, handler));
+        }
+
+        for (segment, child) in other.children {
+            self.children.entry(segment).or_insert_with(Node::new).merge(child);
+        }
+    }
+}
+
+/// A handler dispatching requests by their Uri-Path and method. Unknown paths
+///   are answered with 4.04 Not Found and unsupported methods with
+///   4.05 Method Not Allowed.
+pub struct Router {
+    root: Node,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router { root: Node::new() }
+    }
+
+    /// Registers a handler for the method on the resource path, replacing the
+    ///   previously registered one.
+    pub fn add<H: CoAPHandler + 'static>(&mut self,
+                                         method: Requests,
+                                         path: &str,
+                                         handler: H)
+                                         -> &mut Router {
+        let mut node = Node::new();
+        node.methods.push((method, Box::new(handler)));
+        self.insert(path, node);
+        self
+    }
+
+    /// Registers a handler for GET requests on the resource path.
+    pub fn get<H: CoAPHandler + 'static>(&mut self, path: &str, handler: H) -> &mut Router {
+        self.add(Requests::Get, path, handler)
+    }
+
+    /// Registers a handler for POST requests on the resource path.
+    pub fn post<H: CoAPHandler + 'static>(&mut self, path: &str, handler: H) -> &mut Router {
+        self.add(Requests::Post, path, handler)
+    }
+
+    /// Registers a handler for PUT requests on the resource path.
+    pub fn put<H: CoAPHandler + 'static>(&mut self, path: &str, handler: H) -> &mut Router {
+        self.add(Requests::Put, path, handler)
+    }
+
+    /// Registers a handler for DELETE requests on the resource path.
+    pub fn delete<H: CoAPHandler + 'static>(&mut self, path: &str, handler: H) -> &mut Router {
+        self.add(Requests::Delete, path, handler)
+    }
+
+    /// Registers a handler for FETCH requests on the resource path.
+    pub fn fetch<H: CoAPHandler + 'static>(&mut self, path: &str, handler: H) -> &mut Router {
+        self.add(Requests::Fetch, path, handler)
+    }
+
+    /// Registers a handler for PATCH requests on the resource path.
+    pub fn patch<H: CoAPHandler + 'static>(&mut self, path: &str, handler: H) -> &mut Router {
+        self.add(Requests::Patch, path, handler)
+    }
+
+    /// Registers a handler for iPATCH requests on the resource path.
+    pub fn ipatch<H: CoAPHandler + 'static>(&mut self, path: &str, handler: H) -> &mut Router {
+        self.add(Requests::IPatch, path, handler)
+    }
+
+    /// Mounts all resources of another router under the path prefix.
+    pub fn mount(&mut self, prefix: &str, router: Router) -> &mut Router {
+        self.insert(prefix, router.root);
+        self
+    }
+
+    fn insert(&mut self, path: &str, node: Node) {
+        let mut current = &mut self.root;
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            current = current.children.entry(segment.to_string()).or_insert_with(Node::new);
+        }
+        current.merge(node);
+    }
+
+    fn find(&self, request: &Packet) -> Option<&Node> {
+        let mut current = &self.root;
+        if let Some(segments) = request.get_option(OptionType::UriPath) {
+            for segment in segments.iter() {
+                let segment = String::from_utf8_lossy(segment);
+                match current.children.get(&*segment) {
+                    Some(child) => current = child,
+                    None => return None,
+                }
+            }
+        }
+
+        if current.methods.is_empty() {
+            None
+        } else {
+            Some(current)
+        }
+    }
+}
+
+impl CoAPHandler for Router {
+    fn handle(&self, request: Packet, response: Option<Packet>) -> Option<Packet> {
+        let method = match request.header.code {
+            PacketClass::Request(ref method) => method.clone(),
+            _ => return None,
+        };
+
+        let error = match self.find(&request) {
+            Some(node) => {
+                match node.methods.iter().find(|&&(ref m, _)| *m == method) {
+                    Some(&(_, ref handler)) => return handler.handle(request, response),
+                    None => Responses::MethodNotAllowed,
+                }
+            }
+            None => Responses::NotFound,
+        };
+
+        response.and_then(|_| Packet::response_to(&request, error))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use packet::{Packet, PacketType, PacketClass, Requests, Responses, OptionType,
+                 auto_response};
+    use server::CoAPHandler;
+
+    fn request(method: Requests, path: &str) -> Packet {
+        let mut packet = Packet::new();
+        packet.header.set_version(1);
+        packet.header.set_type(PacketType::Confirmable);
+        packet.header.code = PacketClass::Request(method);
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            packet.add_option(OptionType::UriPath, segment.as_bytes().to_vec());
+        }
+        packet
+    }
+
+    fn dispatch(router: &Router, method: Requests, path: &str) -> Packet {
+        let packet = request(method, path);
+        let response = auto_response(&packet);
+        router.handle(packet, response).unwrap()
+    }
+
+    fn hello(_: Packet, response: Option<Packet>) -> Option<Packet> {
+        response.map(|mut packet| {
+            packet.set_payload(b"hello".to_vec());
+            packet
+        })
+    }
+
+    fn changed(_: Packet, response: Option<Packet>) -> Option<Packet> {
+        response.map(|mut packet| {
+            packet.header.code = PacketClass::Response(Responses::Changed);
+            packet
+        })
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let mut router = Router::new();
+        router.get("/hello", hello).put("/hello", changed).get("/", hello);
+        router.fetch("/hello", hello).patch("/hello", changed).ipatch("/hello", changed);
+
+        assert_eq!(dispatch(&router, Requests::Get, "/hello").payload,
+                   b"hello".to_vec());
+        assert_eq!(dispatch(&router, Requests::Put, "/hello").header.code,
+                   PacketClass::Response(Responses::Changed));
+        assert_eq!(dispatch(&router, Requests::Get, "").payload, b"hello".to_vec());
+        assert_eq!(dispatch(&router, Requests::Fetch, "/hello").payload,
+                   b"hello".to_vec());
+        assert_eq!(dispatch(&router, Requests::Patch, "/hello").header.code,
+                   PacketClass::Response(Responses::Changed));
+        assert_eq!(dispatch(&router, Requests::IPatch, "/hello").header.code,
+                   PacketClass::Response(Responses::Changed));
+    }
+
+    #[test]
+    fn test_not_found() {
+        let mut router = Router::new();
+        router.get("/a/b", hello);
+
+        assert_eq!(dispatch(&router, Requests::Get, "/missing").header.code,
+                   PacketClass::Response(Responses::NotFound));
+        // Intermediate segments are not resources themselves
+        assert_eq!(dispatch(&router, Requests::Get, "/a").header.code,
+                   PacketClass::Response(Responses::NotFound));
+        assert_eq!(dispatch(&router, Requests::Get, "/a/b/c").header.code,
+                   PacketClass::Response(Responses::NotFound));
+    }
+
+    #[test]
+    fn test_method_not_allowed() {
+        let mut router = Router::new();
+        router.get("/hello", hello);
+
+        let response = dispatch(&router, Requests::Delete, "/hello");
+        assert_eq!(response.header.code,
+                   PacketClass::Response(Responses::MethodNotAllowed));
+        assert!(response.payload.is_empty());
+    }
+
+    #[test]
+    fn test_mount() {
+        let mut sensors = Router::new();
+        sensors.get("/temperature", hello).post("/", changed);
+
+        let mut router = Router::new();
+        router.get("/sensors/humidity", hello);
+        router.mount("/sensors", sensors);
+
+        assert_eq!(dispatch(&router, Requests::Get, "/sensors/temperature").payload,
+                   b"hello".to_vec());
+        assert_eq!(dispatch(&router, Requests::Get, "/sensors/humidity").payload,
+                   b"hello".to_vec());
+        assert_eq!(dispatch(&router, Requests::Post, "/sensors").header.code,
+                   PacketClass::Response(Responses::Changed));
+    }
+}
@@ -2,15 +2,27 @@ use std;
 use std::io::{Error, ErrorKind};
 use std::thread;
 use std::net::{ToSocketAddrs, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc;
+use std::time::{Duration, Instant};
 use mio::{EventLoop, PollOpt, EventSet, Handler, Sender, Token};
 use mio::udp::UdpSocket;
-use packet::{Packet, auto_response};
+use packet::{Packet, PacketHeader, PacketType, PacketClass, OptionType, Requests, Responses,
+             BlockValue, OptionDefinition, OptionRegistry, OptionError, class_to_code,
+             is_critical_option, DEFAULT_MAX_MESSAGE_SIZE};
 use threadpool::ThreadPool;
+use rand::random;
+use transmission::TransmissionParameters;
+use dedup::{self, MessageCache, MessageStatus};
+use observe::ObserverRegistry;
+use block::{self, BlockCache};
 
 const DEFAULT_WORKER_NUM: usize = 4;
 pub type TxQueue = mpsc::Sender<CoAPResponse>;
 pub type RxQueue = mpsc::Receiver<CoAPResponse>;
+type AckQueue = mpsc::Sender<(SocketAddr, u16)>;
+type AckRxQueue = mpsc::Receiver<(SocketAddr, u16)>;
 
 #[derive(Debug)]
 pub enum CoAPServerError {
@@ -25,42 +37,538 @@ pub struct CoAPResponse {
     pub response: Packet,
 }
 
-pub trait CoAPHandler: Sync + Send + Copy {
+/// Server state shared between the event loop and the worker threads.
+#[derive(Clone)]
+struct ServerContext {
+    tx_sender: TxQueue,
+    ack_sender: AckQueue,
+    message_ids: Arc<AtomicUsize>,
+    message_cache: Arc<Mutex<MessageCache>>,
+    observers: Arc<Mutex<ObserverRegistry>>,
+    block_cache: Arc<Mutex<BlockCache>>,
+    upload_cache: Arc<Mutex<BlockCache>>,
+    block_size_exponent: u8,
+    max_body_size: usize,
+    max_message_size: usize,
+    local_address: SocketAddr,
+    options: Arc<OptionRegistry>,
+    parameters: TransmissionParameters,
+}
+
+impl ServerContext {
+    /// Records the response in the message cache, so that duplicates of the
+    ///   request get it again, and sends it.
+    fn reply(&self, address: SocketAddr, message_id: u16, response: Packet) {
+        self.message_cache
+            .lock()
+            .unwrap()
+            .set_response(address, message_id, response.clone());
+        self.send(address, response);
+    }
+
+    fn send(&self, address: SocketAddr, response: Packet) {
+        debug!("Response: {:?}", response);
+        self.tx_sender
+            .send(CoAPResponse {
+                address: address,
+                response: response,
+            })
+            .unwrap();
+    }
+}
+
+/// The exchange of a request being handled, given to an `ExchangeHandler`.
+pub struct Exchange {
+    address: SocketAddr,
+    message_type: PacketType,
+    message_id: u16,
+    token: Vec<u8>,
+    context: ServerContext,
+    deferred: AtomicBool,
+}
+
+impl Exchange {
+    fn new(context: ServerContext, address: SocketAddr, request: &Packet) -> Exchange {
+        Exchange {
+            address: address,
+            message_type: request.header.get_type(),
+            message_id: request.header.get_message_id(),
+            token: request.get_token().clone(),
+            context: context,
+            deferred: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the address of the client that sent the request.
+    pub fn peer_address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Returns the address the server listens on, e.g. to compose the URI
+    ///   of the request with `Packet::get_uri`.
+    pub fn local_address(&self) -> SocketAddr {
+        self.context.local_address
+    }
+
+    /// Defers the response of the request. A Confirmable request is
+    ///   acknowledged with an empty ACK right away, and the returned handle
+    ///   sends the actual response later. Returns `None` if the response was
+    ///   already deferred. Once deferred, the return value of the handler is
+    ///   ignored.
+    pub fn defer_response(&self) -> Option<SeparateResponse> {
+        if self.deferred.swap(true, Ordering::SeqCst) {
+            return None;
+        }
+
+        let context = &self.context;
+        let confirmable = self.message_type == PacketType::Confirmable;
+        if confirmable {
+            let mut ack = Packet::new();
+            ack.header.set_version(1);
+            ack.header.set_type(PacketType::Acknowledgement);
+            ack.header.set_message_id(self.message_id);
+            // Duplicates of the request are answered with the same empty ACK
+            context.reply(self.address, self.message_id, ack);
+        }
+
+        Some(SeparateResponse {
+            address: self.address,
+            confirmable: confirmable,
+            token: self.token.clone(),
+            tx_sender: context.tx_sender.clone(),
+            message_ids: context.message_ids.clone(),
+        })
+    }
+
+    fn is_deferred(&self) -> bool {
+        self.deferred.load(Ordering::SeqCst)
+    }
+}
+
+/// A handle to send the response of a request after the handler has returned.
+pub struct SeparateResponse {
+    address: SocketAddr,
+    confirmable: bool,
+    token: Vec<u8>,
+    tx_sender: TxQueue,
+    message_ids: Arc<AtomicUsize>,
+}
+
+impl SeparateResponse {
+    /// Sends the response. A response to a Confirmable request is sent as a
+    ///   Confirmable message and retransmitted until the client acknowledges it.
+    pub fn send(self, mut response: Packet) -> Result<(), CoAPServerError> {
+        response.header.set_version(1);
+        response.header.set_type(if self.confirmable {
+            PacketType::Confirmable
+        } else {
+            PacketType::NonConfirmable
+        });
+        response.header.set_message_id(next_message_id(&self.message_ids));
+        response.set_token(self.token);
+
+        match self.tx_sender.send(CoAPResponse {
+            address: self.address,
+            response: response,
+        }) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(CoAPServerError::NetworkError),
+        }
+    }
+}
+
+fn next_message_id(message_ids: &AtomicUsize) -> u16 {
+    message_ids.fetch_add(1, Ordering::SeqCst) as u16
+}
+
+/// A handle to notify the observers of resources ([RFC 7641][spec]).
+///
+/// A GET or FETCH request with Observe=0 registers the client as an observer
+///   of the resource if the handler answers it with a success response.
+///   Observers are removed when they deregister with Observe=1, reject a
+///   notification with RST or do not acknowledge a Confirmable notification.
+///
+/// [spec]: https://tools.ietf.org/html/rfc7641
+#[derive(Clone)]
+pub struct Notifier {
+    context: ServerContext,
+}
+
+impl Notifier {
+    /// Sends the new state of the resource to all of its observers, each with
+    ///   its own token and the next sequence number. Every so often a
+    ///   notification is sent as Confirmable to check that the observer is still
+    ///   there. A notification with an error response code ends all observations
+    ///   of the resource.
+    pub fn notify(&self, path: &str, notification: Packet) -> Result<(), CoAPServerError> {
+        let message_ids = &self.context.message_ids;
+        let mut observers = self.context.observers.lock().unwrap();
+        let targets = observers.notify(path, || next_message_id(message_ids));
+        if !is_success(&notification.header.code) {
+            observers.remove_all(path);
+        }
+        drop(observers);
+
+        for target in targets {
+            let mut packet = notification.clone();
+            packet.header.set_version(1);
+            packet.header.set_type(if target.confirmable {
+                PacketType::Confirmable
+            } else {
+                PacketType::NonConfirmable
+            });
+            packet.header.set_message_id(target.message_id);
+            packet.set_token(target.token);
+            packet.set_observe(target.sequence);
+
+            if self.context
+                .tx_sender
+                .send(CoAPResponse {
+                    address: target.address,
+                    response: packet,
+                })
+                .is_err() {
+                return Err(CoAPServerError::NetworkError);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_success(code: &PacketClass) -> bool {
+    match *code {
+        PacketClass::Response(_) => class_to_code(code) >> 5 == 2,
+        _ => false,
+    }
+}
+
+/// Returns the Observe option value of a GET or FETCH request.
+fn observe_request(packet: &Packet) -> Option<u32> {
+    match packet.header.code {
+        PacketClass::Request(Requests::Get) |
+        PacketClass::Request(Requests::Fetch) => {}
+        _ => return None,
+    }
+    packet.get_observe()
+}
+
+/// Splits a response larger than the block size into blocks ([RFC 7959][spec])
+///   and returns the requested one. The complete response is kept to serve
+///   the following blocks.
+///
+/// [spec]: https://tools.ietf.org/html/rfc7959
+fn split_response(context: &ServerContext,
+                  src: SocketAddr,
+                  key: &str,
+                  requested: Option<BlockValue>,
+                  response: Packet)
+                  -> Packet {
+    let size_exponent = match requested {
+        Some(requested) if requested.size_exponent < context.block_size_exponent => {
+            requested.size_exponent
+        }
+        _ => context.block_size_exponent,
+    };
+    let offset = requested.map_or(0, |requested| requested.offset());
+    if requested.is_none() && response.payload.len() <= BlockValue::size_of(size_exponent) {
+        return response;
+    }
+
+    // A block number beyond the option range is rejected like one beyond the end
+    let num = (offset >> (size_exponent + 4)) as u32;
+    let block = BlockValue::new(num, false, size_exponent);
+    match block.and_then(|block| block::slice(&response, block)) {
+        Some(packet) => {
+            if packet.get_block2().map_or(false, |b| b.more) {
+                context.block_cache
+                    .lock()
+                    .unwrap()
+                    .insert(src, key, response, context.parameters.exchange_lifetime());
+            }
+            packet
+        }
+        None => {
+            let mut packet = Packet::new();
+            packet.header = response.header.clone();
+            packet.header.code = PacketClass::Response(Responses::BadOption);
+            packet.set_token(response.get_token().clone());
+            packet
+        }
+    }
+}
+
+/// Returns the number of a critical option of the message that is unknown,
+///   repeated without being repeatable, or has a value of invalid length.
+///   Such options must not be ignored ([RFC 7252 section 5.4.1][spec]): a
+///   Confirmable request is answered with 4.02 Bad Option, any other message
+///   is rejected.
+///
+/// [spec]: https://tools.ietf.org/html/rfc7252#section-5.4.1
+fn unrecognized_critical_option(options: &OptionRegistry, packet: &Packet) -> Option<usize> {
+    packet.get_option_numbers().into_iter().find(|&number| {
+        if !is_critical_option(number) {
+            return false;
+        }
+        let definition = match options.get(number) {
+            Some(definition) => definition,
+            None => return true,
+        };
+        let values = packet.get_raw_option(number).unwrap_or_default();
+        (!definition.repeatable && values.len() > 1) ||
+        values.iter().any(|v| v.len() < definition.min_length || v.len() > definition.max_length)
+    })
+}
+
+fn reset(message_id: u16) -> Packet {
+    let mut packet = Packet::new();
+    packet.header.set_version(1);
+    packet.header.set_type(PacketType::Reset);
+    packet.header.set_message_id(message_id);
+    packet
+}
+
+/// Collects the blocks of a request body sent with Block1 ([RFC 7959][spec]).
+///   Returns the request with the complete body once its last block arrived,
+///   or the response to send otherwise: 2.31 Continue for the blocks before the
+///   last one, 4.08 for a block out of order and 4.13 for a body larger than
+///   the maximum body size.
+///
+/// [spec]: https://tools.ietf.org/html/rfc7959
+fn reassemble_request(context: &ServerContext,
+                      src: SocketAddr,
+                      mut request: Packet)
+                      -> Result<Packet, Packet> {
+    let block = match request.get_block1() {
+        Some(block) => block,
+        None if request.payload.len() > context.max_body_size => {
+            return Err(entity_too_large(context, &request));
+        }
+        None => return Ok(request),
+    };
+
+    let key = block::upload_key(&request);
+    let mut uploads = context.upload_cache.lock().unwrap();
+    let mut body = if block.num == 0 {
+        Vec::new()
+    } else {
+        match uploads.get(src, &key) {
+            Some(partial) => partial.payload,
+            None => Vec::new(),
+        }
+    };
+
+    if block.offset() != body.len() {
+        uploads.remove(src, &key);
+        return Err(empty_response(&request, Responses::RequestEntityIncomplete));
+    }
+    let announced = request.get_size1().map_or(0, |size| size as usize);
+    if announced > context.max_body_size ||
+       body.len() + request.payload.len() > context.max_body_size {
+        uploads.remove(src, &key);
+        return Err(entity_too_large(context, &request));
+    }
+    body.extend_from_slice(&request.payload);
+
+    if block.more {
+        let mut partial = Packet::new();
+        partial.payload = body;
+        uploads.insert(src, &key, partial, context.parameters.exchange_lifetime());
+
+        // Ask for smaller blocks if the client sends larger ones than ours
+        let size_exponent = ::std::cmp::min(block.size_exponent, context.block_size_exponent);
+        let mut response = empty_response(&request, Responses::Continue);
+        // The block number was parsed from the request, so it fits in the option
+        let block = BlockValue {
+            more: true,
+            size_exponent: size_exponent,
+            ..block
+        };
+        response.set_block1(block).unwrap();
+        return Err(response);
+    }
+
+    uploads.remove(src, &key);
+    request.payload = body;
+    request.clear_option(OptionType::Block1);
+    request.clear_option(OptionType::Size1);
+    Ok(request)
+}
+
+/// Returns a 4.13 response to the request. Its Size1 option is the largest
+///   body the server accepts, also for a request rejected for the size of the
+///   message, because the client can still send a larger body in blocks.
+fn entity_too_large(context: &ServerContext, request: &Packet) -> Packet {
+    let mut response = empty_response(request, Responses::RequestEntityTooLarge);
+    response.set_size1(context.max_body_size as u32);
+    response
+}
+
+/// Whether the message breaks the rules of RFC 7252 section 4 beyond what
+///   decoding checks: Empty messages carry nothing after the message ID,
+///   Acknowledgements carry no request and Resets are empty.
+fn is_malformed(packet: &Packet) -> bool {
+    match (packet.header.get_type(), &packet.header.code) {
+        (_, &PacketClass::Empty) => {
+            !packet.get_token().is_empty() || !packet.get_option_numbers().is_empty() ||
+            !packet.payload.is_empty()
+        }
+        (PacketType::Reset, _) => true,
+        (PacketType::Acknowledgement, _) => packet.is_request(),
+        _ => false,
+    }
+}
+
+/// Rejects a malformed message: a Confirmable one gets RST, anything else is
+///   silently ignored.
+fn reject_malformed(context: &ServerContext, src: SocketAddr, header: &PacketHeader) {
+    debug!("Rejecting malformed message {} from {}", header.get_message_id(), src);
+    if header.get_version() == 1 && header.get_type() == PacketType::Confirmable {
+        context.send(src, reset(header.get_message_id()));
+    }
+}
+
+/// Answers a message larger than the maximum message size, which may have been
+///   truncated, from its header and token alone. Requests get 4.13 Request
+///   Entity Too Large, anything else is dropped.
+fn reject_oversized(context: &ServerContext, src: SocketAddr, buf: &[u8]) {
+    let header_length = 4 + (buf[0] & 0x0F) as usize;
+    if buf.len() < header_length {
+        return;
+    }
+    let request = match Packet::from_bytes(&buf[..header_length]) {
+        Ok(request) => request,
+        Err(_) => return,
+    };
+    match (request.header.get_type(), &request.header.code) {
+        (PacketType::Confirmable, &PacketClass::Request(_)) |
+        (PacketType::NonConfirmable, &PacketClass::Request(_)) => {
+            if is_duplicate(context, src, &request.header) {
+                return;
+            }
+            warn!("Rejecting a message larger than {} bytes from {}",
+                  context.max_message_size,
+                  src);
+            let response = entity_too_large(context, &request);
+            context.reply(src, request.header.get_message_id(), response);
+        }
+        _ => debug!("Dropping a message larger than {} bytes", context.max_message_size),
+    }
+}
+
+/// Returns a response to the request with the code and no payload.
+fn empty_response(request: &Packet, code: Responses) -> Packet {
+    Packet::response_to(request, code).unwrap()
+}
+
+/// A request handler. It is shared by all worker threads, so any state it
+///   holds must be synchronized, e.g. through an `Arc<Mutex<_>>` captured by a
+///   closure:
+///
+/// ```no_run
+/// use std::sync::{Arc, Mutex};
+/// use coap::CoAPServer;
+/// use coap::packet::Packet;
+///
+/// let counter = Arc::new(Mutex::new(0));
+/// let mut server = CoAPServer::new("127.0.0.1:5683").unwrap();
+/// server.handle(move |_: Packet, response: Option<Packet>| {
+///     let mut count = counter.lock().unwrap();
+///     *count += 1;
+///     response.map(|mut packet| {
+///         packet.set_payload(count.to_string().into_bytes());
+///         packet
+///     })
+/// }).unwrap();
+/// ```
+pub trait CoAPHandler: Sync + Send {
     fn handle(&self, Packet, Option<Packet>) -> Option<Packet>;
 }
 
 impl<F> CoAPHandler for F
     where F: Fn(Packet, Option<Packet>) -> Option<Packet>,
-          F: Sync + Send + Copy
+          F: Sync + Send
 {
     fn handle(&self, request: Packet, response: Option<Packet>) -> Option<Packet> {
         return self(request, response);
     }
 }
 
-struct UdpHandler<H: CoAPHandler + 'static> {
+impl<H: CoAPHandler + ?Sized> CoAPHandler for Arc<H> {
+    fn handle(&self, request: Packet, response: Option<Packet>) -> Option<Packet> {
+        (**self).handle(request, response)
+    }
+}
+
+/// A request handler that is also given the exchange of the request, e.g. to
+///   send a separate response later:
+///
+/// ```no_run
+/// use std::thread;
+/// use coap::CoAPServer;
+/// use coap::packet::Packet;
+/// use coap::server::Exchange;
+///
+/// let mut server = CoAPServer::new("127.0.0.1:5683").unwrap();
+/// server.handle_with_exchange(|exchange: &Exchange, _: Packet, response: Option<Packet>| {
+///     let separate = exchange.defer_response().unwrap();
+///     thread::spawn(move || {
+///         let mut response = response.unwrap();
+///         response.set_payload(b"done".to_vec());
+///         separate.send(response).unwrap();
+///     });
+///     None
+/// }).unwrap();
+/// ```
+pub trait ExchangeHandler: Sync + Send {
+    fn handle(&self, &Exchange, Packet, Option<Packet>) -> Option<Packet>;
+}
+
+impl<F> ExchangeHandler for F
+    where F: Fn(&Exchange, Packet, Option<Packet>) -> Option<Packet>,
+          F: Sync + Send
+{
+    fn handle(&self, exchange: &Exchange, request: Packet, response: Option<Packet>)
+              -> Option<Packet> {
+        self(exchange, request, response)
+    }
+}
+
+/// Calls a `CoAPHandler` without the exchange.
+struct IgnoreExchange<H: CoAPHandler>(H);
+
+impl<H: CoAPHandler> ExchangeHandler for IgnoreExchange<H> {
+    fn handle(&self, _: &Exchange, request: Packet, response: Option<Packet>) -> Option<Packet> {
+        self.0.handle(request, response)
+    }
+}
+
+struct UdpHandler<H: ExchangeHandler + 'static> {
     socket: UdpSocket,
     thread_pool: ThreadPool,
-    tx_sender: TxQueue,
-    coap_handler: H,
+    context: ServerContext,
+    coap_handler: Arc<H>,
+    buf: Vec<u8>,
 }
 
-impl<H: CoAPHandler + 'static> UdpHandler<H> {
+impl<H: ExchangeHandler + 'static> UdpHandler<H> {
     fn new(socket: UdpSocket,
            thread_pool: ThreadPool,
-           tx_sender: TxQueue,
+           context: ServerContext,
            coap_handler: H)
            -> UdpHandler<H> {
+        // One byte more than the maximum tells a larger message apart
+        let buf = vec![0; context.max_message_size + 1];
         UdpHandler {
             socket: socket,
             thread_pool: thread_pool,
-            tx_sender: tx_sender,
-            coap_handler: coap_handler,
+            context: context,
+            coap_handler: Arc::new(coap_handler),
+            buf: buf,
         }
     }
 }
 
-impl<H: CoAPHandler + 'static> Handler for UdpHandler<H> {
+impl<H: ExchangeHandler + 'static> Handler for UdpHandler<H> {
     type Timeout = usize;
     type Message = ();
 
@@ -70,47 +578,40 @@ impl<H: CoAPHandler + 'static> Handler for UdpHandler<H> {
             return;
         }
 
-        let coap_handler = self.coap_handler;
-        let mut buf = [0; 1500];
-
-        match self.socket.recv_from(&mut buf) {
-            Ok(Some((nread, src))) => {
-                debug!("Handling request from {}", src);
-                let response_q = self.tx_sender.clone();
-                self.thread_pool.execute(move || {
-                    match Packet::from_bytes(&buf[..nread]) {
-                        Ok(packet) => {
-                            // Pre-generate a response
-                            let auto_resp = auto_response(&packet);
-                            // Dispatch user handler, if there is a response packet
-                            //   send the reply via the TX thread
-                            match coap_handler.handle(packet, auto_resp) {
-                                Some(response) => {
-                                    debug!("Response: {:?}", response);
-                                    response_q.send(CoAPResponse {
-                                            address: src,
-                                            response: response,
-                                        })
-                                        .unwrap();
-                                }
-                                None => {
-                                    debug!("No response");
-                                }
-                            };
-                        }
-                        Err(_) => {
-                            error!("Failed to parse request");
+        // The socket is registered edge-triggered, so read every datagram
+        //   that is waiting
+        loop {
+            match self.socket.recv_from(&mut self.buf) {
+                Ok(Some((nread, src))) => {
+                    debug!("Handling request from {}", src);
+                    let buf = self.buf[..nread].to_vec();
+                    let coap_handler = self.coap_handler.clone();
+                    let context = self.context.clone();
+                    self.thread_pool.execute(move || {
+                        if nread > context.max_message_size {
+                            reject_oversized(&context, src, &buf);
                             return;
                         }
-                    };
-                });
-            }
-            _ => {
-                error!("Failed to read from socket");
-                panic!("unexpected error");
+                        match Packet::from_bytes(&buf) {
+                            Ok(packet) => handle_packet(&*coap_handler, context, src, packet),
+                            Err(_) if nread >= 4 => {
+                                // Decode the header alone, without the token
+                                let header = [buf[0] & 0xF0, buf[1], buf[2], buf[3]];
+                                if let Ok(packet) = Packet::from_bytes(&header) {
+                                    reject_malformed(&context, src, &packet.header);
+                                }
+                            }
+                            Err(_) => debug!("Dropping a message of {} bytes", nread),
+                        };
+                    });
+                }
+                Ok(None) => break,
+                Err(_) => {
+                    error!("Failed to read from socket");
+                    panic!("unexpected error");
+                }
             }
         }
-
     }
 
     fn notify(&mut self, event_loop: &mut EventLoop<UdpHandler<H>>, _: ()) {
@@ -119,12 +620,168 @@ impl<H: CoAPHandler + 'static> Handler for UdpHandler<H> {
     }
 }
 
+/// Records a Confirmable or Non-confirmable message in the deduplication
+///   cache and returns whether it was seen before. Duplicate Confirmable
+///   messages get the same response again, other duplicates are silently
+///   ignored.
+fn is_duplicate(context: &ServerContext, src: SocketAddr, header: &PacketHeader) -> bool {
+    let message_type = header.get_type();
+    let message_id = header.get_message_id();
+    let lifetime = match message_type {
+        PacketType::Confirmable => context.parameters.exchange_lifetime(),
+        _ => context.parameters.non_lifetime(),
+    };
+    let status = context.message_cache.lock().unwrap().check(src, message_id, lifetime);
+    match status {
+        MessageStatus::Duplicate(cached) => {
+            debug!("Duplicate message {} from {}", message_id, src);
+            if let (PacketType::Confirmable, Some(response)) = (message_type, cached) {
+                context.send(src, response);
+            }
+            true
+        }
+        MessageStatus::New => false,
+    }
+}
+
+/// Handles a received message on a worker thread.
+fn handle_packet<H: ExchangeHandler>(coap_handler: &H,
+                                 context: ServerContext,
+                                 src: SocketAddr,
+                                 packet: Packet) {
+    let message_type = packet.header.get_type();
+    let message_id = packet.header.get_message_id();
+
+    // Messages of other versions are silently ignored
+    if packet.header.get_version() != 1 {
+        return;
+    }
+    if is_malformed(&packet) {
+        reject_malformed(&context, src, &packet.header);
+        return;
+    }
+
+    // Acknowledgements of our Confirmable messages stop
+    //   their retransmission in the TX thread
+    if message_type == PacketType::Acknowledgement || message_type == PacketType::Reset {
+        let mut observers = context.observers.lock().unwrap();
+        if message_type == PacketType::Reset {
+            observers.remove_by_message(src, message_id);
+        } else {
+            observers.acknowledge(src, message_id);
+        }
+        drop(observers);
+        let _ = context.ack_sender.send((src, message_id));
+        return;
+    }
+
+    if is_duplicate(&context, src, &packet.header) {
+        return;
+    }
+
+    // Empty Confirmable messages (CoAP ping) and Confirmable messages that
+    //   are not requests are answered with RST, others are ignored
+    if !packet.is_request() {
+        if message_type == PacketType::Confirmable {
+            context.reply(src, message_id, reset(message_id));
+        }
+        return;
+    }
+
+    if let Some(number) = unrecognized_critical_option(&context.options, &packet) {
+        debug!("Rejecting message {} with critical option {}", message_id, number);
+        let response = match (message_type, &packet.header.code) {
+            (PacketType::Confirmable, &PacketClass::Request(_)) => {
+                empty_response(&packet, Responses::BadOption)
+            }
+            _ => reset(message_id),
+        };
+        context.reply(src, message_id, response);
+        return;
+    }
+
+    // The blocks of a large request body are collected before the handler
+    //   is called with the complete request
+    let block1 = packet.get_block1();
+    let packet = match reassemble_request(&context, src, packet) {
+        Ok(packet) => packet,
+        Err(response) => {
+            context.reply(src, message_id, response);
+            return;
+        }
+    };
+
+    let observe = observe_request(&packet);
+    let path = packet.get_uri_path_string();
+    let token = packet.get_token().clone();
+    let block2 = packet.get_block2();
+    let block_key = block::cache_key(&packet);
+    if observe == Some(1) {
+        context.observers.lock().unwrap().deregister(src, &token);
+    }
+
+    // Pre-generate a response
+    let auto_resp = Packet::default_response_to(&packet);
+
+    // The following blocks of a large response are served from the complete
+    //   response, without calling the handler again
+    if block2.map_or(false, |b| b.num > 0) {
+        let stored = context.block_cache.lock().unwrap().get(src, &block_key);
+        if let (Some(mut stored), Some(template)) = (stored, auto_resp.clone()) {
+            stored.header.set_type(template.header.get_type());
+            stored.header.set_message_id(template.header.get_message_id());
+            stored.set_token(template.get_token().clone());
+            let response = split_response(&context, src, &block_key, block2, stored);
+            context.reply(src, message_id, response);
+            return;
+        }
+    }
+
+    // Dispatch user handler, if there is a response packet
+    //   send the reply via the TX thread
+    let exchange = Exchange::new(context.clone(), src, &packet);
+    let mut result = coap_handler.handle(&exchange, packet, auto_resp);
+    if exchange.is_deferred() {
+        debug!("Response deferred");
+        return;
+    }
+
+    if observe == Some(0) {
+        if let Some(ref mut response) = result {
+            if is_success(&response.header.code) {
+                let sequence = context.observers.lock().unwrap().register(&path, src, token);
+                response.set_observe(sequence);
+            }
+        }
+    }
+
+    match result {
+        Some(mut response) => {
+            if let Some(block1) = block1 {
+                response.set_block1(BlockValue { more: false, ..block1 }).unwrap();
+            }
+            let response = split_response(&context, src, &block_key, block2, response);
+            context.reply(src, message_id, response);
+        }
+        None => {
+            debug!("No response");
+        }
+    };
+}
+
 pub struct CoAPServer {
     socket: UdpSocket,
     event_sender: Option<Sender<()>>,
     event_thread: Option<thread::JoinHandle<()>>,
     tx_thread: Option<thread::JoinHandle<()>>,
     worker_num: usize,
+    parameters: TransmissionParameters,
+    cache_capacity: usize,
+    block_size_exponent: u8,
+    max_body_size: usize,
+    max_message_size: usize,
+    options: OptionRegistry,
+    context: Option<ServerContext>,
 }
 
 impl CoAPServer {
@@ -140,6 +797,13 @@ impl CoAPServer {
                             event_thread: None,
                             tx_thread: None,
                             worker_num: DEFAULT_WORKER_NUM,
+                            parameters: TransmissionParameters::default(),
+                            cache_capacity: dedup::DEFAULT_CAPACITY,
+                            block_size_exponent: block::DEFAULT_SIZE_EXPONENT,
+                            max_body_size: block::DEFAULT_MAX_BODY_SIZE,
+                            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+                            options: OptionRegistry::new(),
+                            context: None,
                         })
                     })
                 }
@@ -150,6 +814,14 @@ impl CoAPServer {
 
     /// Starts handling requests with the handler
     pub fn handle<H: CoAPHandler + 'static>(&mut self, handler: H) -> Result<(), CoAPServerError> {
+        self.handle_with_exchange(IgnoreExchange(handler))
+    }
+
+    /// Starts handling requests with a handler that is also given the
+    ///   exchange of each request.
+    pub fn handle_with_exchange<H: ExchangeHandler + 'static>(&mut self,
+                                                              handler: H)
+                                                              -> Result<(), CoAPServerError> {
         let socket;
 
         // Early return error checking
@@ -164,16 +836,45 @@ impl CoAPServer {
                 return Err(CoAPServerError::NetworkError);
             }
         }
+        let local_address = match self.socket.local_addr() {
+            Ok(address) => address,
+            Err(_) => return Err(CoAPServerError::NetworkError),
+        };
 
         // Create resources
         let worker_num = self.worker_num;
+        let parameters = self.parameters;
         let (tx, rx) = mpsc::channel();
         let (tx_send, tx_recv): (TxQueue, RxQueue) = mpsc::channel();
+        let (ack_send, ack_recv): (AckQueue, AckRxQueue) = mpsc::channel();
         let tx_only = self.socket.try_clone().unwrap();
+        let observers = Arc::new(Mutex::new(ObserverRegistry::new()));
+        let context = ServerContext {
+            tx_sender: tx_send,
+            ack_sender: ack_send,
+            message_ids: Arc::new(AtomicUsize::new(random::<u16>() as usize)),
+            message_cache: Arc::new(Mutex::new(MessageCache::new(self.cache_capacity))),
+            observers: observers.clone(),
+            block_cache: Arc::new(Mutex::new(BlockCache::new(block::DEFAULT_CAPACITY))),
+            upload_cache: Arc::new(Mutex::new(BlockCache::new(block::DEFAULT_CAPACITY))),
+            block_size_exponent: self.block_size_exponent,
+            max_body_size: self.max_body_size,
+            max_message_size: self.max_message_size,
+            local_address: local_address,
+            options: Arc::new(self.options.clone()),
+            parameters: parameters,
+        };
+        let server_context = context.clone();
 
         // Setup and spawn single TX thread
+        let max_message_size = self.max_message_size;
         let tx_thread = thread::spawn(move || {
-            transmit_handler(tx_recv, tx_only);
+            transmit_handler(tx_recv,
+                             ack_recv,
+                             tx_only,
+                             parameters,
+                             max_message_size,
+                             observers);
         });
 
         // Setup and spawn event loop thread, which will spawn
@@ -185,7 +886,7 @@ impl CoAPServer {
 
             tx.send(event_loop.channel()).unwrap();
 
-            event_loop.run(&mut UdpHandler::new(socket, thread_pool, tx_send, handler)).unwrap();
+            event_loop.run(&mut UdpHandler::new(socket, thread_pool, context, handler)).unwrap();
         });
 
         // Ensure threads started successfully
@@ -194,6 +895,7 @@ impl CoAPServer {
                 self.event_sender = Some(event_sender);
                 self.event_thread = Some(thread);
                 self.tx_thread = Some(tx_thread);
+                self.context = Some(server_context);
                 Ok(())
             }
             Err(_) => Err(CoAPServerError::EventLoopError),
@@ -202,6 +904,7 @@ impl CoAPServer {
 
     /// Stop the server.
     pub fn stop(&mut self) {
+        self.context = None;
         let event_sender = self.event_sender.take();
         match event_sender {
             Some(ref sender) => {
@@ -212,35 +915,150 @@ impl CoAPServer {
         }
     }
 
+    /// Returns a handle to notify the observers of resources, once the server
+    ///   is handling requests.
+    pub fn notifier(&self) -> Option<Notifier> {
+        self.context.as_ref().map(|context| Notifier { context: context.clone() })
+    }
+
     /// Set the number of threads for handling requests
     pub fn set_worker_num(&mut self, worker_num: usize) {
         self.worker_num = worker_num;
     }
+
+    /// Set the parameters used to retransmit Confirmable messages and to
+    ///   remember received message IDs (EXCHANGE_LIFETIME and NON_LIFETIME)
+    pub fn set_transmission_parameters(&mut self, parameters: TransmissionParameters) {
+        self.parameters = parameters;
+    }
+
+    /// Set the maximum number of received messages remembered to detect duplicates
+    pub fn set_dedup_capacity(&mut self, capacity: usize) {
+        self.cache_capacity = capacity;
+    }
+
+    /// Set the block size used to split large responses, a power of two from
+    ///   16 to 1024 bytes. Clients may ask for smaller blocks.
+    pub fn set_block_size(&mut self, size: usize) -> std::io::Result<()> {
+        match BlockValue::size_exponent_of(size) {
+            Some(size_exponent) => {
+                self.block_size_exponent = size_exponent;
+                Ok(())
+            }
+            None => Err(Error::new(ErrorKind::InvalidInput, "invalid block size")),
+        }
+    }
+
+    /// Set the largest request body accepted, in bytes. Larger requests are
+    ///   rejected with 4.13 Request Entity Too Large.
+    pub fn set_max_body_size(&mut self, size: usize) {
+        self.max_body_size = size;
+    }
+
+    /// Set the largest message sent or received, in bytes, 1280 by default.
+    ///   Larger requests are rejected with 4.13 Request Entity Too Large.
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.max_message_size = size;
+    }
+
+    /// Declares an option understood by the request handler. Messages with a
+    ///   critical option that is neither defined by the CoAP specifications
+    ///   nor registered are rejected before they reach the handler.
+    pub fn register_option(&mut self, definition: OptionDefinition) -> Result<(), OptionError> {
+        self.options.register(definition)
+    }
+}
+
+/// A Confirmable message waiting for its acknowledgement.
+struct PendingConfirmable {
+    address: SocketAddr,
+    message_id: u16,
+    bytes: Vec<u8>,
+    timeout: Duration,
+    retransmit_at: Instant,
+    retransmit_count: u32,
 }
 
-fn transmit_handler(tx_recv: RxQueue, tx_only: UdpSocket) {
+fn transmit_handler(tx_recv: RxQueue,
+                    ack_recv: AckRxQueue,
+                    tx_only: UdpSocket,
+                    parameters: TransmissionParameters,
+                    max_message_size: usize,
+                    observers: Arc<Mutex<ObserverRegistry>>) {
     // Note! We should only transmit with this UDP Socket
     // TODO: Add better support for failure detection or logging
+    let mut pending: Vec<PendingConfirmable> = Vec::new();
     loop {
-        match tx_recv.recv() {
-            Ok(q_res) => {
-                match q_res.response.to_bytes() {
-                    Ok(bytes) => {
-                        let _ = tx_only.send_to(&bytes[..], &q_res.address);
-                    }
-                    Err(_) => {
-                        error!("Failed to decode response");
+        // Wait for the next response, or the next retransmission
+        let next_retransmit = pending.iter().map(|p| p.retransmit_at).min();
+        let received = match next_retransmit {
+            Some(at) => {
+                let now = Instant::now();
+                let wait = if at > now { at - now } else { Duration::new(0, 0) };
+                match tx_recv.recv_timeout(wait) {
+                    Ok(q_res) => Some(q_res),
+                    Err(mpsc::RecvTimeoutError::Timeout) => None,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            None => {
+                match tx_recv.recv() {
+                    Ok(q_res) => Some(q_res),
+                    Err(_) => break,
+                }
+            }
+        };
+
+        if let Some(q_res) = received {
+            match q_res.response.to_bytes_with_limit(max_message_size) {
+                Ok(bytes) => {
+                    let _ = tx_only.send_to(&bytes[..], &q_res.address);
+
+                    if q_res.response.header.get_type() == PacketType::Confirmable {
+                        let timeout = parameters.initial_timeout();
+                        pending.push(PendingConfirmable {
+                            address: q_res.address,
+                            message_id: q_res.response.header.get_message_id(),
+                            bytes: bytes,
+                            timeout: timeout,
+                            retransmit_at: Instant::now() + timeout,
+                            retransmit_count: 0,
+                        });
                     }
                 }
+                Err(_) => {
+                    error!("Failed to decode response");
+                }
             }
-            // recv error occurs when all transmitters are terminited
-            //   (when all UDP Handlers are closed)
-            Err(_) => {
-                info!("Shutting down Transmit Handler");
-                break;
+        }
+
+        // Stop retransmitting acknowledged messages
+        while let Ok((address, message_id)) = ack_recv.try_recv() {
+            pending.retain(|p| p.address != address || p.message_id != message_id);
+        }
+
+        let now = Instant::now();
+        pending.retain(|p| {
+            if p.retransmit_at <= now && p.retransmit_count >= parameters.max_retransmit {
+                warn!("Message {} to {} was not acknowledged", p.message_id, p.address);
+                // An observer that does not acknowledge a notification is gone
+                observers.lock().unwrap().remove_by_message(p.address, p.message_id);
+                return false;
             }
+            true
+        });
+        for p in pending.iter_mut().filter(|p| p.retransmit_at <= now) {
+            p.retransmit_count += 1;
+            p.timeout = p.timeout * 2;
+            p.retransmit_at = now + p.timeout;
+            debug!("Retransmitting message {} to {}", p.message_id, p.address);
+            let _ = tx_only.send_to(&p.bytes[..], &p.address);
         }
     }
+
+    // recv error occurs when all transmitters are terminited
+    //   (when all UDP Handlers are closed)
+    info!("Shutting down Transmit Handler");
 }
 
 impl Drop for CoAPServer {
@@ -253,8 +1071,15 @@ impl Drop for CoAPServer {
 #[cfg(test)]
 mod test {
     use super::*;
-    use packet::{Packet, PacketType, OptionType};
+    use std::thread;
+    use std::time::Duration;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use packet::{Packet, PacketType, PacketClass, Requests, Responses, OptionType, OptionFormat,
+                 OptionDefinition, BlockValue};
     use client::CoAPClient;
+    use observe;
+    use transmission::TransmissionParameters;
 
     fn request_handler(req: Packet, response: Option<Packet>) -> Option<Packet> {
         let uri_path_list = req.get_option(OptionType::UriPath).unwrap();
@@ -305,4 +1130,619 @@ mod test {
         let recv_packet = client.receive().unwrap();
         assert_eq!(recv_packet.payload, b"test-echo".to_vec());
     }
+
+    fn deferred_handler(exchange: &Exchange, _: Packet, _: Option<Packet>) -> Option<Packet> {
+        let separate = exchange.defer_response().unwrap();
+        assert!(exchange.defer_response().is_none());
+
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            let mut response = Packet::new();
+            response.header.set_code("2.05");
+            response.set_payload(b"later".to_vec());
+            separate.send(response).unwrap();
+        });
+        None
+    }
+
+    fn fast_parameters() -> TransmissionParameters {
+        let mut parameters = TransmissionParameters::new();
+        parameters.ack_timeout = Duration::from_millis(100);
+        parameters.ack_random_factor = 1.0;
+        parameters.max_retransmit = 2;
+        parameters
+    }
+
+    fn confirmable_request() -> Packet {
+        let mut packet = Packet::new();
+        packet.header.set_version(1);
+        packet.header.set_type(PacketType::Confirmable);
+        packet.header.set_code("0.01");
+        packet.header.set_message_id(1);
+        packet.set_token(vec![0x51, 0x55, 0x77, 0xE8]);
+        packet
+    }
+
+    /// A context whose responses can be read from the returned queue.
+    fn test_context() -> (ServerContext, RxQueue) {
+        let (tx_send, tx_recv) = mpsc::channel();
+        let (ack_send, _) = mpsc::channel();
+        let context = ServerContext {
+            tx_sender: tx_send,
+            ack_sender: ack_send,
+            message_ids: Arc::new(AtomicUsize::new(100)),
+            message_cache: Arc::new(Mutex::new(MessageCache::new(dedup::DEFAULT_CAPACITY))),
+            observers: Arc::new(Mutex::new(ObserverRegistry::new())),
+            block_cache: Arc::new(Mutex::new(BlockCache::new(block::DEFAULT_CAPACITY))),
+            upload_cache: Arc::new(Mutex::new(BlockCache::new(block::DEFAULT_CAPACITY))),
+            block_size_exponent: block::DEFAULT_SIZE_EXPONENT,
+            max_body_size: block::DEFAULT_MAX_BODY_SIZE,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            local_address: "127.0.0.1:5683".parse().unwrap(),
+            options: Arc::new(OptionRegistry::new()),
+            parameters: TransmissionParameters::default(),
+        };
+        (context, tx_recv)
+    }
+
+    #[test]
+    fn test_defer_response() {
+        let (context, responses) = test_context();
+        let peer = "127.0.0.1:1".parse().unwrap();
+        let exchange = Exchange::new(context, peer, &confirmable_request());
+        assert_eq!(exchange.peer_address(), peer);
+
+        // The response may be deferred from another thread
+        let separate = thread::scope(|scope| {
+                scope.spawn(|| exchange.defer_response()).join().unwrap()
+            })
+            .unwrap();
+        assert!(exchange.defer_response().is_none());
+        assert!(exchange.is_deferred());
+
+        let ack = responses.try_recv().unwrap();
+        assert_eq!(ack.address, peer);
+        assert_eq!(ack.response.header.get_type(), PacketType::Acknowledgement);
+        assert_eq!(ack.response.header.code, PacketClass::Empty);
+        assert_eq!(ack.response.header.get_message_id(), 1);
+
+        separate.send(Packet::new()).unwrap();
+        let response = responses.try_recv().unwrap().response;
+        assert_eq!(response.header.get_type(), PacketType::Confirmable);
+        assert_eq!(response.header.get_message_id(), 100);
+        assert_eq!(*response.get_token(), vec![0x51, 0x55, 0x77, 0xE8]);
+    }
+
+    #[test]
+    fn test_separate_response() {
+        let mut server = CoAPServer::new("127.0.0.1:5688").unwrap();
+        server.handle_with_exchange(deferred_handler).unwrap();
+
+        let client = CoAPClient::new("127.0.0.1:5688").unwrap();
+        let response = client.send_request(&confirmable_request(), None).unwrap();
+        assert_eq!(response.header.get_type(), PacketType::Confirmable);
+        assert_eq!(response.payload, b"later".to_vec());
+    }
+
+    #[test]
+    fn test_separate_response_retransmission() {
+        let mut server = CoAPServer::new("127.0.0.1:5689").unwrap();
+        server.set_transmission_parameters(fast_parameters());
+        server.handle_with_exchange(deferred_handler).unwrap();
+
+        let client = CoAPClient::new("127.0.0.1:5689").unwrap();
+        client.send(&confirmable_request()).unwrap();
+
+        let ack = client.receive().unwrap();
+        assert_eq!(ack.header.get_type(), PacketType::Acknowledgement);
+        assert_eq!(ack.header.code, PacketClass::Empty);
+        assert_eq!(ack.header.get_message_id(), 1);
+
+        // The unacknowledged response is retransmitted with the same message ID
+        let response = client.receive().unwrap();
+        assert_eq!(response.header.get_type(), PacketType::Confirmable);
+        assert_eq!(*response.get_token(), vec![0x51, 0x55, 0x77, 0xE8]);
+        let retransmission = client.receive().unwrap();
+        assert_eq!(retransmission.header.get_message_id(),
+                   response.header.get_message_id());
+
+        let mut ack = Packet::new();
+        ack.header.set_version(1);
+        ack.header.set_type(PacketType::Acknowledgement);
+        ack.header.set_message_id(response.header.get_message_id());
+        client.send(&ack).unwrap();
+
+        client.set_receive_timeout(Some(Duration::from_millis(500))).unwrap();
+        assert!(client.receive().is_err());
+    }
+
+    static COUNTED_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+    fn counting_handler(_: Packet, response: Option<Packet>) -> Option<Packet> {
+        let count = COUNTED_REQUESTS.fetch_add(1, Ordering::SeqCst) + 1;
+        response.map(|mut packet| {
+            packet.set_payload(count.to_string().into_bytes());
+            packet
+        })
+    }
+
+    #[test]
+    fn test_duplicate_requests() {
+        let mut server = CoAPServer::new("127.0.0.1:5690").unwrap();
+        server.handle(counting_handler).unwrap();
+
+        let client = CoAPClient::new("127.0.0.1:5690").unwrap();
+        let request = confirmable_request();
+        client.send(&request).unwrap();
+        let response = client.receive().unwrap();
+        client.send(&request).unwrap();
+        let duplicate = client.receive().unwrap();
+        assert_eq!(response.payload, duplicate.payload);
+        assert_eq!(duplicate.header.get_message_id(), 1);
+
+        let mut request = confirmable_request();
+        request.header.set_type(PacketType::NonConfirmable);
+        request.header.set_message_id(2);
+        client.send(&request).unwrap();
+        client.receive().unwrap();
+        client.send(&request).unwrap();
+        client.set_receive_timeout(Some(Duration::from_millis(500))).unwrap();
+        assert!(client.receive().is_err());
+
+        assert_eq!(COUNTED_REQUESTS.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_stateful_closure_handler() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+
+        let mut server = CoAPServer::new("127.0.0.1:5691").unwrap();
+        server.handle(move |req: Packet, response: Option<Packet>| {
+                let path = req.get_option(OptionType::UriPath).unwrap();
+                recorded.lock().unwrap().push(path.front().unwrap().clone());
+                response
+            })
+            .unwrap();
+
+        let client = CoAPClient::new("127.0.0.1:5691").unwrap();
+        let mut request = confirmable_request();
+        request.add_option(OptionType::UriPath, b"state".to_vec());
+        client.send_request(&request, None).unwrap();
+
+        assert_eq!(*requests.lock().unwrap(), vec![b"state".to_vec()]);
+    }
+
+    struct Greeter {
+        greeting: String,
+    }
+
+    impl CoAPHandler for Greeter {
+        fn handle(&self, _: Packet, response: Option<Packet>) -> Option<Packet> {
+            response.map(|mut packet| {
+                packet.set_payload(self.greeting.clone().into_bytes());
+                packet
+            })
+        }
+    }
+
+    #[test]
+    fn test_shared_handler() {
+        let greeter = Arc::new(Greeter { greeting: "hello".to_string() });
+
+        let mut server1 = CoAPServer::new("127.0.0.1:5692").unwrap();
+        server1.handle(greeter.clone()).unwrap();
+        let mut server2 = CoAPServer::new("127.0.0.1:5693").unwrap();
+        server2.handle(greeter.clone()).unwrap();
+
+        for addr in ["127.0.0.1:5692", "127.0.0.1:5693"].iter() {
+            let client = CoAPClient::new(addr).unwrap();
+            let response = client.send_request(&confirmable_request(), None).unwrap();
+            assert_eq!(response.payload, b"hello".to_vec());
+        }
+    }
+
+    fn observe_request(observe: u8) -> Packet {
+        let mut request = confirmable_request();
+        request.add_option(OptionType::UriPath, b"temp".to_vec());
+        request.add_option(OptionType::Observe, vec![observe]);
+        request
+    }
+
+    fn notification(payload: &[u8]) -> Packet {
+        let mut packet = Packet::new();
+        packet.header.set_code("2.05");
+        packet.set_payload(payload.to_vec());
+        packet
+    }
+
+    fn reply(client: &CoAPClient, message_type: PacketType, message_id: u16) {
+        let mut packet = Packet::new();
+        packet.header.set_version(1);
+        packet.header.set_type(message_type);
+        packet.header.set_message_id(message_id);
+        client.send(&packet).unwrap();
+    }
+
+    #[test]
+    fn test_observe() {
+        let mut server = CoAPServer::new("127.0.0.1:5694").unwrap();
+        server.set_transmission_parameters(fast_parameters());
+        server.handle(request_handler).unwrap();
+        let notifier = server.notifier().unwrap();
+
+        let client = CoAPClient::new("127.0.0.1:5694").unwrap();
+        let response = client.send_request(&observe_request(0), None).unwrap();
+        let observe = response.get_option(OptionType::Observe).unwrap();
+        assert_eq!(*observe.front().unwrap(), Vec::<u8>::new());
+
+        notifier.notify("/temp", notification(b"21")).unwrap();
+        let first = client.receive().unwrap();
+        assert_eq!(first.header.get_type(), PacketType::NonConfirmable);
+        assert_eq!(*first.get_token(), vec![0x51, 0x55, 0x77, 0xE8]);
+        assert_eq!(first.payload, b"21".to_vec());
+        assert_eq!(*first.get_option(OptionType::Observe).unwrap().front().unwrap(),
+                   vec![1]);
+
+        // Every so often a notification is Confirmable
+        let mut confirmable = None;
+        for _ in 1..observe::CONFIRMABLE_INTERVAL {
+            notifier.notify("/temp", notification(b"22")).unwrap();
+            let packet = client.receive().unwrap();
+            if packet.header.get_type() == PacketType::Confirmable {
+                confirmable = Some(packet);
+            }
+        }
+        let confirmable = confirmable.unwrap();
+        reply(&client, PacketType::Acknowledgement, confirmable.header.get_message_id());
+
+        // Rejecting a notification ends the observation
+        notifier.notify("/temp", notification(b"23")).unwrap();
+        let mut last = client.receive().unwrap();
+        // Skip a retransmission of the Confirmable notification, if any
+        while last.payload != b"23".to_vec() {
+            last = client.receive().unwrap();
+        }
+        reply(&client, PacketType::Reset, last.header.get_message_id());
+        thread::sleep(Duration::from_millis(100));
+        notifier.notify("/temp", notification(b"24")).unwrap();
+        client.set_receive_timeout(Some(Duration::from_millis(500))).unwrap();
+        assert!(client.receive().is_err());
+    }
+
+    #[test]
+    fn test_observe_deregister() {
+        let mut server = CoAPServer::new("127.0.0.1:5695").unwrap();
+        server.handle(request_handler).unwrap();
+        let notifier = server.notifier().unwrap();
+
+        let client = CoAPClient::new("127.0.0.1:5695").unwrap();
+        client.send_request(&observe_request(0), None).unwrap();
+
+        let mut deregister = observe_request(1);
+        deregister.header.set_message_id(2);
+        let response = client.send_request(&deregister, None).unwrap();
+        assert!(response.get_option(OptionType::Observe).is_none());
+
+        notifier.notify("/temp", notification(b"21")).unwrap();
+        client.set_receive_timeout(Some(Duration::from_millis(500))).unwrap();
+        assert!(client.receive().is_err());
+    }
+
+    static LARGE_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+    fn large_payload() -> Vec<u8> {
+        (0..3000).map(|i| (i % 256) as u8).collect()
+    }
+
+    fn large_handler(_: Packet, response: Option<Packet>) -> Option<Packet> {
+        LARGE_REQUESTS.fetch_add(1, Ordering::SeqCst);
+        response.map(|mut packet| {
+            packet.set_payload(large_payload());
+            packet
+        })
+    }
+
+    #[test]
+    fn test_block2() {
+        let mut server = CoAPServer::new("127.0.0.1:5697").unwrap();
+        server.handle(large_handler).unwrap();
+
+        let client = CoAPClient::new("127.0.0.1:5697").unwrap();
+        let response = client.send_request(&confirmable_request(), None).unwrap();
+        assert_eq!(response.payload, large_payload());
+        assert!(response.get_block2().is_none());
+        // The following blocks are served without calling the handler again
+        assert_eq!(LARGE_REQUESTS.load(Ordering::SeqCst), 1);
+
+        let mut client = CoAPClient::new("127.0.0.1:5697").unwrap();
+        client.set_block_size(64).unwrap();
+        let response = client.send_request(&confirmable_request(), None).unwrap();
+        assert_eq!(response.payload, large_payload());
+    }
+
+    #[test]
+    fn test_block2_size_negotiation() {
+        let mut server = CoAPServer::new("127.0.0.1:5698").unwrap();
+        server.set_block_size(512).unwrap();
+        server.handle(large_handler).unwrap();
+
+        let client = CoAPClient::new("127.0.0.1:5698").unwrap();
+        let mut request = confirmable_request();
+        request.set_block2(BlockValue::new(0, false, 2).unwrap()).unwrap();
+        client.send(&request).unwrap();
+        let response = client.receive().unwrap();
+        assert_eq!(response.payload.len(), 64);
+        assert_eq!(response.get_block2(), BlockValue::new(0, true, 2));
+        let etag = response.get_option(OptionType::ETag);
+        assert!(etag.is_some());
+
+        // Blocks larger than the server block size are not sent
+        request.header.set_message_id(2);
+        request.set_block2(BlockValue::new(1, false, 6).unwrap()).unwrap();
+        client.send(&request).unwrap();
+        let response = client.receive().unwrap();
+        assert_eq!(response.payload, large_payload()[1024..1536].to_vec());
+        assert_eq!(response.get_block2(), BlockValue::new(2, true, 5));
+        assert_eq!(response.get_option(OptionType::ETag), etag);
+
+        request.header.set_message_id(3);
+        request.set_block2(BlockValue::new(100, false, 5).unwrap()).unwrap();
+        client.send(&request).unwrap();
+        let response = client.receive().unwrap();
+        assert_eq!(response.header.code, PacketClass::Response(Responses::BadOption));
+    }
+
+    fn upload_request(payload: Vec<u8>) -> Packet {
+        let mut packet = confirmable_request();
+        packet.header.set_code("0.03");
+        packet.add_option(OptionType::UriPath, b"config".to_vec());
+        packet.payload = payload;
+        packet
+    }
+
+    #[test]
+    fn test_block1() {
+        let mut server = CoAPServer::new("127.0.0.1:5699").unwrap();
+        server.set_block_size(256).unwrap();
+        server.handle(|request: Packet, response: Option<Packet>| {
+            assert!(request.get_block1().is_none());
+            response.map(|mut packet| {
+                packet.set_payload(request.payload);
+                packet
+            })
+        }).unwrap();
+
+        // The handler echoes the reassembled body, which is sent back in blocks
+        let client = CoAPClient::new("127.0.0.1:5699").unwrap();
+        let response = client.send_request(&upload_request(large_payload()), None).unwrap();
+        assert_eq!(response.header.code, PacketClass::Response(Responses::Changed));
+        assert_eq!(response.payload, large_payload());
+    }
+
+    #[test]
+    fn test_block1_errors() {
+        let mut server = CoAPServer::new("127.0.0.1:5700").unwrap();
+        server.set_max_body_size(100);
+        server.handle(request_handler).unwrap();
+
+        let client = CoAPClient::new("127.0.0.1:5700").unwrap();
+        let mut request = upload_request(vec![0; 64]);
+        request.set_block1(BlockValue::new(0, true, 2).unwrap()).unwrap();
+        client.send(&request).unwrap();
+        let response = client.receive().unwrap();
+        assert_eq!(response.header.code, PacketClass::Response(Responses::Continue));
+        assert_eq!(response.get_block1(), BlockValue::new(0, true, 2));
+        assert!(response.payload.is_empty());
+
+        request.header.set_message_id(2);
+        request.set_block1(BlockValue::new(2, true, 2).unwrap()).unwrap();
+        client.send(&request).unwrap();
+        let response = client.receive().unwrap();
+        assert_eq!(response.header.code,
+                   PacketClass::Response(Responses::RequestEntityIncomplete));
+
+        request.header.set_message_id(3);
+        request.set_block1(BlockValue::new(0, true, 2).unwrap()).unwrap();
+        request.set_size1(3000);
+        client.send(&request).unwrap();
+        let response = client.receive().unwrap();
+        assert_eq!(response.header.code,
+                   PacketClass::Response(Responses::RequestEntityTooLarge));
+        assert_eq!(response.get_size1(), Some(100));
+
+        let mut request = upload_request(vec![0; 200]);
+        request.header.set_message_id(4);
+        let response = client.send_request(&request, None).unwrap();
+        assert_eq!(response.header.code,
+                   PacketClass::Response(Responses::RequestEntityTooLarge));
+        assert_eq!(response.get_size1(), Some(100));
+    }
+
+    #[test]
+    fn test_request_uri() {
+        let mut server = CoAPServer::new("127.0.0.1:5702").unwrap();
+        let handler = |exchange: &Exchange, request: Packet, response: Option<Packet>| {
+            response.map(|mut packet| {
+                let address = exchange.local_address();
+                let uri = request.get_uri(&address.ip().to_string(), address.port());
+                packet.set_payload(uri.into_bytes());
+                packet
+            })
+        };
+        server.handle_with_exchange(handler).unwrap();
+
+        let uri = "coap://127.0.0.1:5702/a%20b/c?x=1&y";
+        let response = CoAPClient::get(uri).unwrap();
+        assert_eq!(response.payload, uri.as_bytes().to_vec());
+    }
+
+    #[test]
+    fn test_critical_options() {
+        let mut server = CoAPServer::new("127.0.0.1:5703").unwrap();
+        let definition = OptionDefinition::new(2049, "Experimental", OptionFormat::Opaque,
+                                               false, 0, 8);
+        server.register_option(definition).unwrap();
+        server.handle(request_handler).unwrap();
+        let client = CoAPClient::new("127.0.0.1:5703").unwrap();
+
+        let mut request = confirmable_request();
+        request.add_option(OptionType::UriPath, b"test".to_vec());
+        request.add_raw_option(2049, vec![1]);
+        request.add_raw_option(2050, vec![2]);
+        let response = client.send_request(&request, None).unwrap();
+        assert_eq!(response.payload, b"test".to_vec());
+
+        request.header.set_message_id(2);
+        request.add_raw_option(2051, vec![3]);
+        let response = client.send_request(&request, None).unwrap();
+        assert_eq!(response.header.get_type(), PacketType::Acknowledgement);
+        assert_eq!(response.header.code, PacketClass::Response(Responses::BadOption));
+        assert!(response.payload.is_empty());
+
+        // A critical option that is not repeatable must only occur once
+        request.header.set_message_id(3);
+        request.clear_raw_option(2051);
+        request.add_raw_option(2049, vec![4]);
+        let response = client.send_request(&request, None).unwrap();
+        assert_eq!(response.header.code, PacketClass::Response(Responses::BadOption));
+
+        request.header.set_message_id(4);
+        request.header.set_type(PacketType::NonConfirmable);
+        client.send(&request).unwrap();
+        let response = client.receive().unwrap();
+        assert_eq!(response.header.get_type(), PacketType::Reset);
+        assert_eq!(response.header.get_message_id(), 4);
+    }
+
+    #[test]
+    fn test_fetch_block2() {
+        let mut server = CoAPServer::new("127.0.0.1:5704").unwrap();
+        server.handle(|request: Packet, response: Option<Packet>| {
+            response.map(|mut packet| {
+                let mut payload = Vec::new();
+                for _ in 0..1000 {
+                    payload.extend_from_slice(&request.payload);
+                }
+                packet.set_payload(payload);
+                packet
+            })
+        }).unwrap();
+
+        // The body of every block request selects the response again
+        let mut client = CoAPClient::new("127.0.0.1:5704").unwrap();
+        client.set_block_size(256).unwrap();
+        let mut request = confirmable_request();
+        request.header.code = PacketClass::Request(Requests::Fetch);
+        for (message_id, query) in [b"ab", b"cd"].iter().enumerate() {
+            request.header.set_message_id(message_id as u16);
+            request.payload = query.to_vec();
+            let response = client.send_request(&request, None).unwrap();
+            assert_eq!(response.payload.len(), 2000);
+            assert!(response.payload.chunks(2).all(|chunk| chunk == &query[..]));
+        }
+    }
+
+    #[test]
+    fn test_oversized_request() {
+        let mut server = CoAPServer::new("127.0.0.1:5705").unwrap();
+        server.set_max_message_size(100);
+        server.handle(request_handler).unwrap();
+
+        let client = CoAPClient::new("127.0.0.1:5705").unwrap();
+        let request = upload_request(vec![0; 50]);
+        let response = client.send_request(&request, None).unwrap();
+        assert_eq!(response.payload, b"config".to_vec());
+
+        let mut request = upload_request(vec![0; 200]);
+        request.header.set_message_id(2);
+        let response = client.send_request(&request, None).unwrap();
+        assert_eq!(response.header.get_type(), PacketType::Acknowledgement);
+        assert_eq!(response.header.code,
+                   PacketClass::Response(Responses::RequestEntityTooLarge));
+        assert_eq!(response.get_size1(), Some(block::DEFAULT_MAX_BODY_SIZE as u32));
+        assert_eq!(*response.get_token(), vec![0x51, 0x55, 0x77, 0xE8]);
+
+        // A retransmission gets the same rejection again
+        use std::net::UdpSocket;
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::new(2, 0))).unwrap();
+        request.header.set_message_id(3);
+        let bytes = request.to_bytes().unwrap();
+        let mut buf = [0; 1500];
+        for _ in 0..2 {
+            socket.send_to(&bytes[..], "127.0.0.1:5705").unwrap();
+            let nread = socket.recv(&mut buf).unwrap();
+            let response = Packet::from_bytes(&buf[..nread]).unwrap();
+            assert_eq!(response.header.get_message_id(), 3);
+            assert_eq!(response.header.code,
+                       PacketClass::Response(Responses::RequestEntityTooLarge));
+        }
+    }
+
+    #[test]
+    fn test_message_layer() {
+        use std::net::UdpSocket;
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let mut server = CoAPServer::new("127.0.0.1:5707").unwrap();
+        server.handle(move |_: Packet, response: Option<Packet>| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            response
+        }).unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::new(2, 0))).unwrap();
+        client.connect("127.0.0.1:5707").unwrap();
+        let receive = || {
+            let mut buf = [0; 1500];
+            let nread = client.recv(&mut buf).unwrap();
+            Packet::from_bytes(&buf[..nread]).unwrap()
+        };
+
+        // A CoAP ping is answered with RST
+        client.send(&[0x40, 0x00, 0x00, 0x0A]).unwrap();
+        let reset = receive();
+        assert_eq!(reset.header.get_type(), PacketType::Reset);
+        assert_eq!(reset.header.code, PacketClass::Empty);
+        assert_eq!(reset.header.get_message_id(), 10);
+
+        // Malformed Confirmable messages are rejected: a token longer than
+        //   8 bytes, an option with the reserved delta 15, an Empty message
+        //   with a token
+        let mut token_too_long = vec![0x49, 0x01, 0x00, 0x0B];
+        token_too_long.extend(vec![0; 9]);
+        for (message, message_id) in vec![(token_too_long, 11),
+                                          (vec![0x40, 0x01, 0x00, 0x0C, 0xF0], 12),
+                                          (vec![0x41, 0x00, 0x00, 0x0D, 0x01], 13)] {
+            client.send(&message).unwrap();
+            let reset = receive();
+            assert_eq!(reset.header.get_type(), PacketType::Reset);
+            assert_eq!(reset.header.get_message_id(), message_id);
+        }
+
+        // Malformed Non-confirmable messages, Non-confirmable pings,
+        //   Confirmable messages of another version, ACKs and RSTs are
+        //   ignored: the next message received is the RST to a ping
+        client.send(&[0x50, 0x01, 0x00, 0x0E, 0xF0]).unwrap();
+        client.send(&[0x50, 0x00, 0x00, 0x0F]).unwrap();
+        client.send(&[0x80, 0x01, 0x00, 0x10]).unwrap();
+        client.send(&[0x60, 0x00, 0x00, 0x11]).unwrap();
+        client.send(&[0x70, 0x00, 0x00, 0x12]).unwrap();
+        client.send(&[0x70, 0x01, 0x00, 0x13]).unwrap();
+        client.send(&[0x60, 0x45, 0x00, 0x14]).unwrap();
+        client.send(&[0x40, 0x00, 0x00, 0x15]).unwrap();
+        assert_eq!(receive().header.get_message_id(), 0x15);
+
+        // A Confirmable response is not a request
+        client.send(&[0x40, 0x45, 0x00, 0x16]).unwrap();
+        assert_eq!(receive().header.get_type(), PacketType::Reset);
+
+        client.send(&[0x40, 0x01, 0x00, 0x17]).unwrap();
+        let response = receive();
+        assert_eq!(response.header.get_type(), PacketType::Acknowledgement);
+        assert_eq!(response.header.code, PacketClass::Response(Responses::Content));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
 }
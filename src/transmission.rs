@@ -0,0 +1,129 @@
+//! Message transmission parameters ([RFC 7252 section 4.8][spec]).
+//!
+//! [spec]: https://tools.ietf.org/html/rfc7252#section-4.8
+
+use std::time::Duration;
+use rand::{thread_rng, Rng};
+
+const DEFAULT_ACK_TIMEOUT: u64 = 2;  // 2s
+const DEFAULT_ACK_RANDOM_FACTOR: f64 = 1.5;
+const DEFAULT_MAX_RETRANSMIT: u32 = 4;
+const DEFAULT_MAX_LATENCY: u64 = 100;  // 100s
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransmissionParameters {
+    /// ACK_TIMEOUT, the initial retransmission timeout of a Confirmable message.
+    pub ack_timeout: Duration,
+    /// ACK_RANDOM_FACTOR, must not be smaller than 1.0.
+    pub ack_random_factor: f64,
+    /// MAX_RETRANSMIT, the number of retransmissions before giving up.
+    pub max_retransmit: u32,
+    /// MAX_LATENCY, the maximum time a datagram is expected to take in the network.
+    pub max_latency: Duration,
+}
+
+impl Default for TransmissionParameters {
+    fn default() -> TransmissionParameters {
+        TransmissionParameters {
+            ack_timeout: Duration::new(DEFAULT_ACK_TIMEOUT, 0),
+            ack_random_factor: DEFAULT_ACK_RANDOM_FACTOR,
+            max_retransmit: DEFAULT_MAX_RETRANSMIT,
+            max_latency: Duration::new(DEFAULT_MAX_LATENCY, 0),
+        }
+    }
+}
+
+impl TransmissionParameters {
+    /// Creates the parameters with the default values of the specification.
+    pub fn new() -> TransmissionParameters {
+        Self::default()
+    }
+
+    /// Returns a random initial timeout between ACK_TIMEOUT and
+    ///   ACK_TIMEOUT * ACK_RANDOM_FACTOR.
+    pub fn initial_timeout(&self) -> Duration {
+        let factor = if self.ack_random_factor > 1.0 {
+            thread_rng().gen_range(1.0, self.ack_random_factor)
+        } else {
+            1.0
+        };
+        scale(self.ack_timeout, factor)
+    }
+
+    /// MAX_TRANSMIT_SPAN, the maximum time from the first transmission of a
+    ///   Confirmable message to its last retransmission.
+    pub fn max_transmit_span(&self) -> Duration {
+        let backoff = backoff(self.max_retransmit);
+        scale(self.ack_timeout, backoff as f64 * self.ack_random_factor)
+    }
+
+    /// MAX_TRANSMIT_WAIT, the maximum time from the first transmission of a
+    ///   Confirmable message to the time when the sender gives up on
+    ///   receiving an acknowledgement or reset.
+    pub fn max_transmit_wait(&self) -> Duration {
+        let backoff = backoff(self.max_retransmit.saturating_add(1));
+        scale(self.ack_timeout, backoff as f64 * self.ack_random_factor)
+    }
+
+    /// EXCHANGE_LIFETIME, the time a message ID of a Confirmable message is
+    ///   remembered to detect duplicates. PROCESSING_DELAY is taken as ACK_TIMEOUT.
+    pub fn exchange_lifetime(&self) -> Duration {
+        self.max_transmit_span() + self.max_latency * 2 + self.ack_timeout
+    }
+
+    /// NON_LIFETIME, the time a message ID of a Non-confirmable message is
+    ///   remembered to detect duplicates.
+    pub fn non_lifetime(&self) -> Duration {
+        self.max_transmit_span() + self.max_latency
+    }
+}
+
+/// Returns 2^exponent - 1, the sum of the doubling timeouts in units of the
+///   first one, saturating for large exponents.
+fn backoff(exponent: u32) -> u64 {
+    1u64.checked_shl(exponent).map_or(u64::MAX, |power| power - 1)
+}
+
+fn scale(duration: Duration, factor: f64) -> Duration {
+    let millis = duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64;
+    let scaled = (millis as f64 * factor) as u64;
+    Duration::from_millis(scaled)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_default_parameters() {
+        let parameters = TransmissionParameters::new();
+        assert_eq!(parameters.max_transmit_span(), Duration::new(45, 0));
+        assert_eq!(parameters.max_transmit_wait(), Duration::new(93, 0));
+        assert_eq!(parameters.exchange_lifetime(), Duration::new(247, 0));
+        assert_eq!(parameters.non_lifetime(), Duration::new(145, 0));
+    }
+
+    #[test]
+    fn test_large_max_retransmit() {
+        let mut parameters = TransmissionParameters::new();
+        parameters.max_retransmit = 20;
+        assert!(parameters.max_transmit_wait() > parameters.max_transmit_span());
+        for &max_retransmit in &[64, 200, u32::MAX] {
+            parameters.max_retransmit = max_retransmit;
+            assert_eq!(parameters.max_transmit_span(), parameters.max_transmit_wait());
+            parameters.exchange_lifetime();
+        }
+    }
+
+    #[test]
+    fn test_initial_timeout() {
+        let parameters = TransmissionParameters::new();
+        for _ in 0..100 {
+            let timeout = parameters.initial_timeout();
+            assert!(timeout >= Duration::new(2, 0));
+            assert!(timeout <= Duration::new(3, 0));
+        }
+    }
+}